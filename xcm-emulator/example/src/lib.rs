@@ -1,13 +1,23 @@
-use frame_support::{pallet_prelude::Weight, traits::GenesisBuild};
+use frame_support::{
+	pallet_prelude::Weight,
+	storage::unhashed,
+	traits::{GenesisBuild, OnRuntimeUpgrade},
+};
 use sp_runtime::AccountId32;
+use xcm::v3::NetworkId;
 
-use xcm_emulator::{decl_test_network, decl_test_parachain, decl_test_relay_chain};
+use xcm_emulator::{decl_test_bridge, decl_test_network, decl_test_parachain, decl_test_relay_chain, HrmpChannelConfig};
+
+/// Pallets of Kusama whose hooks run in its blocks. `ParaInherent` is left out, as its `on_finalize`
+/// panics in a block without the inherent.
+pub type KusamaPallets = (kusama_runtime::Scheduler, kusama_runtime::Paras);
 
 decl_test_relay_chain! {
 	pub struct KusamaNet {
 		Runtime = kusama_runtime::Runtime,
 		XcmConfig = kusama_runtime::xcm_config::XcmConfig,
 		new_ext = kusama_ext(),
+		AllPalletsWithoutSystem = KusamaPallets,
 	}
 }
 
@@ -18,6 +28,9 @@ decl_test_parachain! {
 		XcmpMessageHandler = yayoi::XcmpQueue,
 		DmpMessageHandler = yayoi::DmpQueue,
 		new_ext = yayoi_ext(1),
+		XcmConfig = yayoi::XcmConfig,
+		AllPalletsWithoutSystem = yayoi::AllPalletsWithoutSystem,
+		Migrations = PumpkinMigrations,
 	}
 }
 
@@ -28,6 +41,8 @@ decl_test_parachain! {
 		XcmpMessageHandler = yayoi::XcmpQueue,
 		DmpMessageHandler = yayoi::DmpQueue,
 		new_ext = yayoi_ext(2),
+		XcmConfig = yayoi::XcmConfig,
+		AllPalletsWithoutSystem = yayoi::AllPalletsWithoutSystem,
 	}
 }
 
@@ -38,6 +53,8 @@ decl_test_parachain! {
 		XcmpMessageHandler = yayoi::XcmpQueue,
 		DmpMessageHandler = yayoi::DmpQueue,
 		new_ext = yayoi_ext(3),
+		XcmConfig = yayoi::XcmConfig,
+		AllPalletsWithoutSystem = yayoi::AllPalletsWithoutSystem,
 	}
 }
 
@@ -49,6 +66,125 @@ decl_test_network! {
 			(2, YayoiMushroom),
 			(3, YayoiOctopus),
 		],
+		hrmp_channels = vec![
+			(1, 2, HRMP_CHANNEL_CONFIG),
+			(2, 1, HRMP_CHANNEL_CONFIG),
+			(2, 3, HRMP_CHANNEL_CONFIG),
+			(3, 2, SMALL_HRMP_CHANNEL_CONFIG),
+		],
+		bridge = KusamaPolkadotBridge,
+	}
+}
+
+decl_test_bridge! {
+	pub struct KusamaPolkadotBridge {
+		bridge_hubs = vec![
+			(NetworkId::Kusama, YayoiMushroom, yayoi::XcmRouter),
+			(NetworkId::Polkadot, polkadot::PolkadotBridgeHub, yayoi::XcmRouter),
+		],
+	}
+}
+
+/// A network of Polkadot consensus, bridged with `Network` through `KusamaPolkadotBridge`. Its
+/// parachains have the same para ids as those of `Network`, as each network has its own queues.
+pub mod polkadot {
+	use super::*;
+
+	decl_test_relay_chain! {
+		pub struct PolkadotNet {
+			Runtime = polkadot_runtime::Runtime,
+			XcmConfig = polkadot_runtime::xcm_config::XcmConfig,
+			new_ext = polkadot_ext(),
+		}
+	}
+
+	decl_test_parachain! {
+		pub struct PolkadotPumpkin {
+			Runtime = yayoi::Runtime,
+			RuntimeOrigin = yayoi::RuntimeOrigin,
+			XcmpMessageHandler = yayoi::XcmpQueue,
+			DmpMessageHandler = yayoi::DmpQueue,
+			new_ext = polkadot_yayoi_ext(1),
+			AllPalletsWithoutSystem = yayoi::AllPalletsWithoutSystem,
+		}
+	}
+
+	decl_test_parachain! {
+		pub struct PolkadotBridgeHub {
+			Runtime = yayoi::Runtime,
+			RuntimeOrigin = yayoi::RuntimeOrigin,
+			XcmpMessageHandler = yayoi::XcmpQueue,
+			DmpMessageHandler = yayoi::DmpQueue,
+			new_ext = polkadot_yayoi_ext(2),
+			AllPalletsWithoutSystem = yayoi::AllPalletsWithoutSystem,
+		}
+	}
+
+	decl_test_network! {
+		pub struct PolkadotNetwork {
+			relay_chain = PolkadotNet,
+			parachains = vec![
+				(1, PolkadotPumpkin),
+				(2, PolkadotBridgeHub),
+			],
+			bridge = super::KusamaPolkadotBridge,
+		}
+	}
+
+	pub fn polkadot_yayoi_ext(para_id: u32) -> sp_io::TestExternalities {
+		let mut ext = yayoi_ext(para_id);
+		ext.execute_with(|| yayoi::RelayNetwork::set(&NetworkId::Polkadot));
+		ext
+	}
+
+	pub fn polkadot_ext() -> sp_io::TestExternalities {
+		use polkadot_runtime::{Runtime, System};
+
+		let mut t = frame_system::GenesisConfig::default()
+			.build_storage::<Runtime>()
+			.unwrap();
+
+		pallet_balances::GenesisConfig::<Runtime> {
+			balances: vec![(ALICE, INITIAL_BALANCE)],
+		}
+		.assimilate_storage(&mut t)
+		.unwrap();
+
+		polkadot_runtime_parachains::configuration::GenesisConfig::<Runtime> {
+			config: default_parachains_host_configuration(),
+		}
+		.assimilate_storage(&mut t)
+		.unwrap();
+
+		let mut ext = sp_io::TestExternalities::new(t);
+		ext.execute_with(|| System::set_block_number(1));
+		ext
+	}
+}
+
+pub const HRMP_CHANNEL_CONFIG: HrmpChannelConfig = HrmpChannelConfig {
+	max_capacity: 8,
+	max_total_size: 8 * 1024,
+	max_message_size: 1024,
+};
+
+/// Config of the HRMP channel from Octopus to Mushroom, which two small pages or one large page fill.
+pub const SMALL_HRMP_CHANNEL_CONFIG: HrmpChannelConfig = HrmpChannelConfig {
+	max_capacity: 2,
+	max_total_size: 1024,
+	max_message_size: 1024,
+};
+
+/// Storage key of the number of runtime upgrades of Pumpkin.
+pub const PUMPKIN_UPGRADES: &[u8] = b":pumpkin:upgrades";
+
+/// Migrations of Pumpkin runtime upgrades, counting the upgrades.
+pub struct PumpkinMigrations;
+
+impl OnRuntimeUpgrade for PumpkinMigrations {
+	fn on_runtime_upgrade() -> Weight {
+		unhashed::put(PUMPKIN_UPGRADES, &(unhashed::get_or_default::<u32>(PUMPKIN_UPGRADES) + 1));
+		Weight::zero()
 	}
 }
 
@@ -98,7 +234,7 @@ fn default_parachains_host_configuration(
 		max_upward_queue_count: 8,
 		max_upward_queue_size: 1024 * 1024,
 		max_downward_message_size: 1024,
-		ump_service_total_weight: Weight::from_parts(4 * 1_000_000_000, 0),
+		ump_service_total_weight: Weight::from_parts(4 * 1_000_000_000, 4 * 1024 * 1024),
 		max_upward_message_size: 50 * 1024,
 		max_upward_message_num_per_candidate: 5,
 		hrmp_sender_deposit: 0,
@@ -152,9 +288,28 @@ mod tests {
 
 	use cumulus_primitives_core::ParaId;
 	use frame_support::{assert_ok, dispatch::GetDispatchInfo, traits::Currency};
+	use polkadot_parachain::primitives::ValidationCode;
 	use sp_runtime::traits::AccountIdConversion;
 	use xcm::{v3::prelude::*, VersionedMultiLocation, VersionedXcm};
-	use xcm_emulator::TestExt;
+	use xcm_emulator::{
+		assert_expected_events, assert_no_events, AsyncBackingParams, ChainId, ChannelSignal, HrmpRouting,
+		MessageDelivery, MessageFilter, MessageKind, MessageStatus, OverweightQueue, TestExt, XcmpMessageFormat,
+		XcmpPage, XcmpPageItem,
+	};
+
+	// pattern of the remarks of the yayoi chains
+	macro_rules! remarked {
+		() => {
+			yayoi::RuntimeEvent::System(frame_system::Event::Remarked { .. })
+		};
+	}
+
+	// pattern of the upward messages executed by Kusama
+	macro_rules! executed_upward {
+		() => {
+			kusama_runtime::RuntimeEvent::Ump(polkadot_runtime_parachains::ump::Event::ExecutedUpward(..))
+		};
+	}
 
 	#[test]
 	fn dmp() {
@@ -173,21 +328,19 @@ mod tests {
 				Parachain(1),
 				Xcm(vec![Transact {
 					origin_kind: OriginKind::SovereignAccount,
-					require_weight_at_most: Weight::from_parts(INITIAL_BALANCE as u64, 1024 * 1024),
+					require_weight_at_most: Weight::from_parts(1_000_000_000, 16 * 1024),
 					call: remark.encode().into(),
 				}]),
 			));
 		});
 
-		YayoiPumpkin::execute_with(|| {
-			use yayoi::{RuntimeEvent, System};
-			System::events().iter().for_each(|r| println!(">>> {:?}", r.event));
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, [remarked!()]);
 
-			assert!(System::events().iter().any(|r| matches!(
-				r.event,
-				RuntimeEvent::System(frame_system::Event::Remarked { sender: _, hash: _ })
-			)));
-		});
+		let downward = Network::messages(MessageFilter::default().kind(MessageKind::Downward));
+		assert_eq!(downward.len(), 1);
+		assert_eq!((downward[0].sender, downward[0].recipient), (ChainId::Relay, ChainId::Parachain(1)));
+		assert_eq!(downward[0].status(), MessageStatus::Complete);
 	}
 
 	#[test]
@@ -224,117 +377,269 @@ mod tests {
 					},
 					Transact {
 						origin_kind: OriginKind::SovereignAccount,
-						require_weight_at_most: Weight::from_parts(INITIAL_BALANCE as u64, 1024 * 1024),
+						require_weight_at_most: Weight::from_parts(1_000_000_000, 16 * 1024),
 						call: remark.encode().into(),
 					}
 				]),
 			));
 		});
 
+		KusamaNet::execute_with(|| {});
+		// TODO: https://github.com/paritytech/polkadot/pull/6824
+		// kusama_runtime::RuntimeEvent::System(frame_system::Event::Remarked { .. }),
+		assert_expected_events!(
+			KusamaNet,
+			[kusama_runtime::RuntimeEvent::Ump(polkadot_runtime_parachains::ump::Event::ExecutedUpward(
+				_,
+				Outcome::Incomplete(_, XcmError::NoPermission)
+			))]
+		);
+	}
+
+	#[test]
+	fn ump_deferred_by_weight_limit() {
+		Network::reset();
+
 		KusamaNet::execute_with(|| {
-			use kusama_runtime::{RuntimeEvent, System};
-			// TODO: https://github.com/paritytech/polkadot/pull/6824
-			// assert!(System::events().iter().any(|r| matches!(
-			// 	r.event,
-			// 	RuntimeEvent::System(frame_system::Event::Remarked { sender: _, hash: _ })
-			// )));
-			assert!(System::events().iter().any(|r| matches!(
-				r.event,
-				RuntimeEvent::Ump(polkadot_runtime_parachains::ump::Event::ExecutedUpward(
-					_,
-					Outcome::Incomplete(_, XcmError::NoPermission)
-				))
-			)));
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			for _ in 0..2 {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					Parent,
+					Xcm(vec![
+						UnpaidExecution {
+							weight_limit: Unlimited,
+							check_origin: None,
+						},
+						Transact {
+							origin_kind: OriginKind::SovereignAccount,
+							require_weight_at_most: Weight::from_parts(2_500_000_000, 16 * 1024),
+							call: remark.encode().into(),
+						}
+					]),
+				));
+			}
 		});
+
+		// only one message fits in `ump_service_total_weight` of a relay chain block
+		assert_eq!(Network::deferred_upward_messages().len(), 1);
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!()]);
+
+		// the deferred message is executed in the next relay chain block
+		YayoiPumpkin::execute_with(|| {});
+		assert!(Network::deferred_upward_messages().is_empty());
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!(), executed_upward!()]);
 	}
 
 	#[test]
-	fn xcmp() {
+	fn ump_first_message_of_block_up_to_max_individual_weight() {
 		Network::reset();
 
-		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
-			remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
 		});
+
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
 		YayoiPumpkin::execute_with(|| {
-			assert_ok!(yayoi::PolkadotXcm::send_xcm(
-				Here,
-				MultiLocation::new(1, X1(Parachain(2))),
-				Xcm(vec![Transact {
-					origin_kind: OriginKind::SovereignAccount,
-					require_weight_at_most: 20_000_000.into(),
-					call: remark.encode().into(),
-				}]),
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
 			));
+			// the second message exceeds `ump_service_total_weight`, but not `ump_max_individual_weight`
+			for weight in [2_500_000_000, 10_000_000_000] {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					Parent,
+					Xcm(vec![
+						UnpaidExecution {
+							weight_limit: Unlimited,
+							check_origin: None,
+						},
+						Transact {
+							origin_kind: OriginKind::SovereignAccount,
+							require_weight_at_most: Weight::from_parts(weight, 16 * 1024),
+							call: remark.encode().into(),
+						}
+					]),
+				));
+			}
 		});
 
-		YayoiMushroom::execute_with(|| {
-			use yayoi::{RuntimeEvent, System};
-			System::events().iter().for_each(|r| println!(">>> {:?}", r.event));
+		// the heavy message doesn't fit in the weight left by the first one
+		assert_eq!(Network::deferred_upward_messages().len(), 1);
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!()]);
 
-			assert!(System::events().iter().any(|r| matches!(
-				r.event,
-				RuntimeEvent::System(frame_system::Event::Remarked { sender: _, hash: _ })
-			)));
-		});
+		// as the first message of the next relay chain block, it may use `ump_max_individual_weight`
+		YayoiPumpkin::execute_with(|| {});
+		assert!(Network::deferred_upward_messages().is_empty());
+		assert!(Network::overweight_messages().is_empty());
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!(), executed_upward!()]);
 	}
 
 	#[test]
-	fn xcmp_through_a_parachain() {
-		use yayoi::{PolkadotXcm, Runtime, RuntimeCall};
-
+	fn ump_limited_per_candidate() {
 		Network::reset();
 
-		// The message goes through: Pumpkin --> Mushroom --> Octopus
-		let remark = RuntimeCall::System(frame_system::Call::<Runtime>::remark_with_event {
-			remark: "Hello from Pumpkin!".as_bytes().to_vec(),
-		});
-		let send_xcm_to_octopus = RuntimeCall::PolkadotXcm(pallet_xcm::Call::<Runtime>::send {
-			dest: Box::new(VersionedMultiLocation::V3(MultiLocation::new(1, X1(Parachain(3))))),
-			message: Box::new(VersionedXcm::V3(Xcm(vec![Transact {
-				origin_kind: OriginKind::SovereignAccount,
-				require_weight_at_most: 10_000_000.into(),
-				call: remark.encode().into(),
-			}]))),
+		// fewer upward messages per candidate than in the default host configuration
+		let mut config = KusamaNet::host_configuration();
+		config.max_upward_message_num_per_candidate = 3;
+		KusamaNet::execute_with(|| {
+			kusama_runtime::Configuration::force_set_active_config(config);
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
 		});
-		assert_eq!(
-			send_xcm_to_octopus.get_dispatch_info().weight,
-			Weight::from_parts(110000010, 10000010)
-		);
+
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
 		YayoiPumpkin::execute_with(|| {
-			assert_ok!(PolkadotXcm::send_xcm(
-				Here,
-				MultiLocation::new(1, X1(Parachain(2))),
-				Xcm(vec![Transact {
-					origin_kind: OriginKind::SovereignAccount,
-					require_weight_at_most: 110_000_010.into(),
-					call: send_xcm_to_octopus.encode().into(),
-				}]),
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
 			));
+			for _ in 0..7 {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					Parent,
+					Xcm(vec![
+						UnpaidExecution {
+							weight_limit: Unlimited,
+							check_origin: None,
+						},
+						Transact {
+							origin_kind: OriginKind::SovereignAccount,
+							require_weight_at_most: Weight::from_parts(100_000_000, 16 * 1024),
+							call: remark.encode().into(),
+						}
+					]),
+				));
+			}
 		});
 
-		YayoiMushroom::execute_with(|| {
-			use yayoi::{RuntimeEvent, System};
-			System::events().iter().for_each(|r| println!(">>> {:?}", r.event));
+		// `max_upward_message_num_per_candidate` is 3, the rest stay pending in Pumpkin
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!(), executed_upward!(), executed_upward!()]);
 
-			assert!(System::events()
-				.iter()
-				.any(|r| matches!(r.event, RuntimeEvent::PolkadotXcm(pallet_xcm::Event::Sent(_, _, _)))));
-		});
+		YayoiPumpkin::execute_with(|| {});
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(
+			KusamaNet,
+			only [
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+			]
+		);
 
-		YayoiOctopus::execute_with(|| {
-			use yayoi::{RuntimeEvent, System};
-			// execution would fail, but good enough to check if the message is received
-			System::events().iter().for_each(|r| println!(">>> {:?}", r.event));
+		YayoiPumpkin::execute_with(|| {});
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(
+			KusamaNet,
+			only [
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+				executed_upward!(),
+			]
+		);
+	}
+
+	#[test]
+	fn relay_parent_follows_relay_chain() {
+		Network::reset();
+
+		let relay_block = KusamaNet::block_number();
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_block);
+
+		// a new parachain block needs a newer relay parent
+		YayoiPumpkin::execute_with(|| {});
+		assert_eq!(KusamaNet::block_number(), relay_block + 1);
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_block + 1);
+
+		// other parachains build on the same relay parent
+		YayoiMushroom::execute_with(|| {});
+		assert_eq!(YayoiMushroom::last_relay_parent_number(), relay_block + 1);
+
+		KusamaNet::execute_with(|| kusama_runtime::System::set_block_number(relay_block + 5));
+		YayoiPumpkin::execute_with(|| {});
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_block + 5);
+	}
+
+	#[test]
+	fn upgrade_signals() {
+		use frame_support::{
+			storage::migration::{get_storage_value, put_storage_value},
+			traits::PalletInfoAccess,
+			StorageHasher, Twox64Concat,
+		};
+		use polkadot_primitives::UpgradeRestriction;
+
+		Network::reset();
+
+		KusamaNet::execute_with(|| {
+			put_storage_value(
+				kusama_runtime::Paras::name().as_bytes(),
+				b"UpgradeRestrictionSignal",
+				&Twox64Concat::hash(&ParaId::from(1).encode()),
+				UpgradeRestriction::Present,
+			);
+		});
+		assert_eq!(
+			KusamaNet::upgrade_signals(1.into()),
+			(None, Some(UpgradeRestriction::Present))
+		);
 
-			assert!(System::events().iter().any(|r| matches!(
-				r.event,
-				RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Fail { .. })
-			)));
+		// the signal is in the relay chain state proof of the parachain
+		YayoiPumpkin::execute_with(|| {
+			assert_eq!(
+				get_storage_value::<Option<UpgradeRestriction>>(
+					yayoi::ParachainSystem::name().as_bytes(),
+					b"UpgradeRestrictionSignal",
+					&[]
+				),
+				Some(Some(UpgradeRestriction::Present))
+			);
 		});
 	}
 
 	#[test]
-	fn deduplicate_dmp() {
+	fn dmq_mqc_head_from_relay_chain() {
+		use frame_support::{storage::migration::get_storage_value, traits::PalletInfoAccess};
+		use xcm_emulator::MessageQueueChain;
+
 		Network::reset();
 		KusamaNet::execute_with(|| {
 			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
@@ -344,72 +649,1530 @@ mod tests {
 		});
 
 		kusama_send_rmrk("Kusama", 2);
-		parachain_receive_and_reset_events(true);
-
-		// a different dmp message in same relay-parent-block allow execution.
-		kusama_send_rmrk("Polkadot", 1);
-		parachain_receive_and_reset_events(true);
-
-		// same dmp message with same relay-parent-block wouldn't execution
-		kusama_send_rmrk("Kusama", 1);
-		parachain_receive_and_reset_events(false);
+		YayoiPumpkin::execute_with(|| {});
 
-		// different relay-parent-block allow dmp message execution
-		KusamaNet::execute_with(|| kusama_runtime::System::set_block_number(2));
+		// the parachain arrives at the head of the relay chain `dmp` pallet
+		let relay_head =
+			KusamaNet::execute_with(|| xcm_emulator::dmq_mqc_head(kusama_runtime::Dmp::name(), 1.into()));
+		assert_ne!(relay_head, Default::default());
+		let head = YayoiPumpkin::execute_with(|| {
+			get_storage_value::<MessageQueueChain>(yayoi::ParachainSystem::name().as_bytes(), b"LastDmqMqcHead", &[])
+				.map(|head| head.head())
+		});
+		assert_eq!(head, Some(relay_head));
+	}
 
-		kusama_send_rmrk("Kusama", 1);
-		parachain_receive_and_reset_events(true);
+	#[test]
+	#[should_panic]
+	fn dmq_mqc_head_diverging_from_relay_chain() {
+		use frame_support::{
+			storage::migration::put_storage_value, traits::PalletInfoAccess, StorageHasher, Twox64Concat,
+		};
 
-		// reset can send same dmp message again
 		Network::reset();
 		KusamaNet::execute_with(|| {
 			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
 				kusama_runtime::RuntimeOrigin::root(),
 				Some(3)
 			));
+			put_storage_value(
+				kusama_runtime::Dmp::name().as_bytes(),
+				b"DownwardMessageQueueHeads",
+				&Twox64Concat::hash(&ParaId::from(1).encode()),
+				xcm_emulator::RelayHash::repeat_byte(1),
+			);
 		});
 
+		// the messages don't lead the parachain to the head of the relay chain
 		kusama_send_rmrk("Kusama", 1);
-		parachain_receive_and_reset_events(true);
+		YayoiPumpkin::execute_with(|| {});
 	}
 
-	fn kusama_send_rmrk(msg: &str, count: u32) {
-		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
-			remark: msg.as_bytes().to_vec(),
+	#[test]
+	fn chain_traits() {
+		use polkadot_parachain::primitives::Sibling;
+		use xcm_emulator::{AccountIdOf, Chain, Parachain as _, RelayChain as _};
+
+		fn sibling_account_on<S: xcm_emulator::Parachain, D: xcm_emulator::Parachain>() -> AccountIdOf<D::Runtime> {
+			D::sovereign_account_id_of_sibling(S::para_id())
+		}
+
+		Network::reset();
+
+		assert_eq!(YayoiPumpkin::para_id(), ParaId::from(1));
+		assert_eq!(
+			KusamaNet::sovereign_account_id_of_child_para(1.into()),
+			ParaId::from(1).into_account_truncating()
+		);
+		assert_eq!(
+			sibling_account_on::<YayoiPumpkin, YayoiMushroom>(),
+			Sibling::from(1).into_account_truncating()
+		);
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::System::remark_with_event(
+				yayoi::RuntimeOrigin::signed(ALICE),
+				vec![1]
+			));
 		});
-		KusamaNet::execute_with(|| {
-			for _ in 0..count {
-				assert_ok!(kusama_runtime::XcmPallet::send_xcm(
-					Here,
-					Parachain(1),
-					Xcm(vec![Transact {
-						origin_kind: OriginKind::SovereignAccount,
-						require_weight_at_most: Weight::from_parts(INITIAL_BALANCE as u64, 1024 * 1024),
-						call: remark.encode().into(),
-					}]),
-				));
+		assert_expected_events!(YayoiPumpkin, [remarked!()]);
+	}
+
+	#[allow(dead_code)]
+	mod misconfigured {
+		use super::*;
+		use xcm_emulator::{decl_test_network, decl_test_parachain, decl_test_relay_chain};
+
+		decl_test_relay_chain! {
+			pub struct MisconfiguredRelay {
+				Runtime = kusama_runtime::Runtime,
+				XcmConfig = kusama_runtime::xcm_config::XcmConfig,
+				new_ext = kusama_ext(),
 			}
-		});
+		}
+
+		decl_test_parachain! {
+			pub struct WrongParaId {
+				Runtime = yayoi::Runtime,
+				RuntimeOrigin = yayoi::RuntimeOrigin,
+				XcmpMessageHandler = yayoi::XcmpQueue,
+				DmpMessageHandler = yayoi::DmpQueue,
+				new_ext = yayoi_ext(4),
+				XcmConfig = yayoi::XcmConfig,
+			}
+		}
+
+		decl_test_parachain! {
+			pub struct WrongRelayNetwork {
+				Runtime = yayoi::Runtime,
+				RuntimeOrigin = yayoi::RuntimeOrigin,
+				XcmpMessageHandler = yayoi::XcmpQueue,
+				DmpMessageHandler = yayoi::DmpQueue,
+				new_ext = polkadot::polkadot_yayoi_ext(5),
+				XcmConfig = yayoi::XcmConfig,
+			}
+		}
+
+		decl_test_network! {
+			pub struct MisconfiguredNetwork {
+				relay_chain = MisconfiguredRelay,
+				parachains = vec![
+					(3, WrongParaId),
+					(5, WrongRelayNetwork),
+				],
+				hrmp_channels = vec![
+					(3, 5),
+					(5, 5),
+					(5, 6),
+				],
+			}
+		}
+	}
+
+	#[test]
+	fn validate() {
+		use misconfigured::MisconfiguredNetwork;
+		use xcm_emulator::{Misconfiguration, ValidationReport};
+
+		assert_eq!(Network::validate(), Ok(()));
+		assert_eq!(polkadot::PolkadotNetwork::validate(), Ok(()));
+
+		assert_eq!(
+			MisconfiguredNetwork::validate(),
+			Err(ValidationReport {
+				network: "MisconfiguredNetwork",
+				misconfigurations: vec![
+					Misconfiguration::ParaIdMismatch {
+						chain: "WrongParaId",
+						declared: 3,
+						actual: 4,
+					},
+					Misconfiguration::UniversalLocationMismatch {
+						chain: "WrongParaId",
+						expected: X2(GlobalConsensus(NetworkId::Kusama), Parachain(3)),
+						actual: X2(GlobalConsensus(NetworkId::Kusama), Parachain(4)),
+					},
+					Misconfiguration::UniversalLocationMismatch {
+						chain: "WrongRelayNetwork",
+						expected: X2(GlobalConsensus(NetworkId::Kusama), Parachain(5)),
+						actual: X2(GlobalConsensus(NetworkId::Polkadot), Parachain(5)),
+					},
+					Misconfiguration::InvalidHrmpChannel { sender: 5, recipient: 5 },
+					Misconfiguration::InvalidHrmpChannel { sender: 5, recipient: 6 },
+				],
+			})
+		);
 	}
 
-	fn parachain_receive_and_reset_events(received: bool) {
+	#[test]
+	#[should_panic(expected = "network MisconfiguredNetwork is misconfigured")]
+	fn reset_misconfigured_network() {
+		misconfigured::MisconfiguredNetwork::reset();
+	}
+
+	#[test]
+	fn produce_blocks() {
+		Network::reset();
+
+		Network::produce_blocks(2);
+		KusamaNet::execute_with(|| assert_eq!(kusama_runtime::System::block_number(), 3));
+		YayoiPumpkin::execute_with(|| assert_eq!(yayoi::System::block_number(), 3));
+
+		YayoiMushroom::produce_block();
+		YayoiMushroom::execute_with(|| assert_eq!(yayoi::System::block_number(), 4));
+		YayoiOctopus::execute_with(|| assert_eq!(yayoi::System::block_number(), 3));
+
+		// an upward message deferred by the weight limit of a relay chain block
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
 		YayoiPumpkin::execute_with(|| {
-			use yayoi::{RuntimeEvent, System};
-			System::events().iter().for_each(|r| println!(">>> {:?}", r.event));
-
-			if received {
-				assert!(System::events().iter().any(|r| matches!(
-					r.event,
-					RuntimeEvent::System(frame_system::Event::Remarked { sender: _, hash: _ })
-				)));
-
-				System::reset_events();
-			} else {
-				assert!(System::events().iter().all(|r| !matches!(
-					r.event,
-					RuntimeEvent::System(frame_system::Event::Remarked { sender: _, hash: _ })
-				)));
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			for _ in 0..2 {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					Parent,
+					Xcm(vec![
+						UnpaidExecution {
+							weight_limit: Unlimited,
+							check_origin: None,
+						},
+						Transact {
+							origin_kind: OriginKind::SovereignAccount,
+							require_weight_at_most: Weight::from_parts(2_500_000_000, 16 * 1024),
+							call: remark.encode().into(),
+						}
+					]),
+				));
 			}
 		});
+		assert_eq!(Network::deferred_upward_messages().len(), 1);
+
+		// and a call scheduled for the last block of the next round
+		let remark = kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark {
+			remark: "Hello from Kusama!".as_bytes().to_vec(),
+		});
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::Scheduler::schedule(
+				kusama_runtime::RuntimeOrigin::root(),
+				kusama_runtime::System::block_number() + 2,
+				None,
+				0,
+				Box::new(remark),
+			));
+		});
+
+		// the relay chain services its upward message queue, and its scheduler dispatches the call in its
+		// hooks, the only event left of the round as events are cleared in each block
+		Network::produce_blocks(2);
+		assert!(Network::deferred_upward_messages().is_empty());
+		let executed = MessageFilter::default().kind(MessageKind::Upward).status(MessageStatus::Complete);
+		assert_eq!(Network::messages(executed).len(), 2);
+		assert_expected_events!(KusamaNet, only [kusama_runtime::RuntimeEvent::Scheduler(..)]);
+	}
+
+	#[test]
+	fn xcmp() {
+		Network::reset();
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, [remarked!()]);
+
+		let horizontal = Network::messages(MessageFilter::default().kind(MessageKind::Horizontal));
+		assert_eq!(horizontal.len(), 1);
+		assert_eq!(
+			(horizontal[0].sender, horizontal[0].recipient),
+			(ChainId::Parachain(1), ChainId::Parachain(2))
+		);
+		assert_eq!(horizontal[0].status(), MessageStatus::Complete);
+	}
+
+	#[test]
+	fn event_assertions() {
+		use sp_runtime::traits::Hash;
+		use yayoi::{Runtime, RuntimeEvent};
+
+		Network::reset();
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+
+		let remark_hash = <Runtime as frame_system::Config>::Hashing::hash(b"Hello from Pumpkin!");
+		assert_expected_events!(
+			YayoiMushroom,
+			[
+				RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Success { .. }),
+				RuntimeEvent::System(frame_system::Event::Remarked { hash, .. }) => { hash: *hash == remark_hash },
+			]
+		);
+		assert_expected_events!(
+			YayoiMushroom,
+			in order [
+				RuntimeEvent::System(frame_system::Event::Remarked { .. }),
+				RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Success { .. }),
+			]
+		);
+		assert_no_events!(
+			YayoiMushroom,
+			[
+				RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Fail { .. }),
+				RuntimeEvent::System(frame_system::Event::Remarked { hash, .. }) => { hash: *hash != remark_hash },
+			]
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "matched, but not its conditions [\"hash\"]")]
+	fn unmet_event_conditions() {
+		use yayoi::RuntimeEvent;
+
+		Network::reset();
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+
+		assert_expected_events!(
+			YayoiMushroom,
+			[RuntimeEvent::System(frame_system::Event::Remarked { hash, .. }) => { hash: *hash == Default::default() }]
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "not found")]
+	fn repeated_event_pattern() {
+		Network::reset();
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::System::remark_with_event(yayoi::RuntimeOrigin::signed(ALICE), vec![1]));
+		});
+		assert_expected_events!(YayoiPumpkin, [remarked!()]);
+
+		// the remark matches one of the patterns only
+		assert_expected_events!(YayoiPumpkin, [remarked!(), remarked!()]);
+	}
+
+	#[test]
+	#[should_panic(expected = "also matched by")]
+	fn only_expected_events() {
+		Network::reset();
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::System::remark_with_event(yayoi::RuntimeOrigin::signed(ALICE), vec![1]));
+			assert_ok!(yayoi::System::remark_with_event(yayoi::RuntimeOrigin::signed(ALICE), vec![2]));
+		});
+		assert_expected_events!(YayoiPumpkin, only [remarked!(), remarked!()]);
+
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+	}
+
+	#[test]
+	fn xcmp_without_hrmp_channel() {
+		Network::reset();
+
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: "Hello from Octopus!".as_bytes().to_vec(),
+		});
+		YayoiOctopus::execute_with(|| {
+			assert_eq!(
+				yayoi::PolkadotXcm::send_xcm(
+					Here,
+					MultiLocation::new(1, X1(Parachain(1))),
+					Xcm(vec![Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: 20_000_000.into(),
+						call: remark.encode().into(),
+					}]),
+				),
+				Err(SendError::Transport("NoChannel"))
+			);
+		});
+	}
+
+	#[test]
+	fn deregistered_parachain() {
+		use polkadot_runtime_parachains::paras::Pallet as Paras;
+
+		Network::reset();
+
+		Network::deregister_parachain(3);
+		assert_eq!(Network::registered_parachains(), vec![1, 2]);
+		KusamaNet::execute_with(|| assert!(!Paras::<kusama_runtime::Runtime>::is_valid_para(3.into())));
+
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: "Hello from Mushroom!".as_bytes().to_vec(),
+		});
+		let send_remark_to_octopus = || {
+			yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(3))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 20_000_000.into(),
+					call: remark.encode().into(),
+				}]),
+			)
+		};
+
+		// channels of an offboarded parachain are closed
+		YayoiMushroom::execute_with(|| {
+			assert_eq!(send_remark_to_octopus(), Err(SendError::Transport("NoChannel")));
+		});
+
+		Network::register_parachain(3);
+		KusamaNet::execute_with(|| assert!(Paras::<kusama_runtime::Runtime>::is_valid_para(3.into())));
+
+		YayoiMushroom::execute_with(|| assert_ok!(send_remark_to_octopus()));
+		YayoiOctopus::execute_with(|| {});
+		assert_expected_events!(YayoiOctopus, only [remarked!()]);
+	}
+
+	#[test]
+	fn dmp_to_deregistered_parachain() {
+		use polkadot_primitives::runtime_api::runtime_decl_for_parachain_host::ParachainHostV4;
+
+		Network::reset();
+		Network::set_message_delivery(MessageDelivery::Inherent);
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		// a message waiting for the next block of Pumpkin is dropped when it's offboarded
+		kusama_send_rmrk("Kusama", 1);
+		Network::deregister_parachain(1);
+		let downward = Network::messages(MessageFilter::default().kind(MessageKind::Downward));
+		assert_eq!(downward[0].status(), MessageStatus::Dropped);
+
+		// and so are messages sent to it while offboarded
+		kusama_send_rmrk("Polkadot", 1);
+		let downward = Network::messages(MessageFilter::default().kind(MessageKind::Downward));
+		assert_eq!(downward.len(), 2);
+		assert_eq!(downward[1].status(), MessageStatus::Dropped);
+		KusamaNet::execute_with(|| assert!(kusama_runtime::Runtime::dmq_contents(1.into()).is_empty()));
+	}
+
+	#[test]
+	fn ump_from_deregistered_parachain() {
+		Network::reset();
+		Network::deregister_parachain(1);
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(yayoi::RuntimeOrigin::root(), Some(3)));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(Here, Parent, Xcm(vec![ClearOrigin])));
+		});
+
+		let upward = Network::messages(MessageFilter::default().kind(MessageKind::Upward));
+		assert_eq!(upward.len(), 1);
+		assert_eq!(upward[0].status(), MessageStatus::Dropped);
+		KusamaNet::execute_with(|| {});
+		assert_no_events!(KusamaNet, [executed_upward!()]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Upward message from YayoiPumpkin to KusamaNet dropped, parachain 1 is not registered")]
+	fn strict_mode_dropped_message() {
+		Network::reset();
+		Network::set_strict_mode(true);
+		Network::deregister_parachain(1);
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(yayoi::RuntimeOrigin::root(), Some(3)));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(Here, Parent, Xcm(vec![ClearOrigin])));
+		});
+	}
+
+	#[test]
+	fn reset_single_chain() {
+		use polkadot_runtime_parachains::paras::Pallet as Paras;
+
+		Network::reset();
+
+		Network::produce_blocks(3);
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+
+		// Mushroom starts over, the other chains go on
+		Network::reset_parachain(2);
+		YayoiMushroom::execute_with(|| {});
+		assert_no_events!(YayoiMushroom, [remarked!()]);
+		let pumpkin_block = YayoiPumpkin::execute_with(yayoi::System::block_number);
+		assert!(YayoiMushroom::execute_with(yayoi::System::block_number) < pumpkin_block);
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+
+		// parachains stay registered in the new relay chain
+		Network::reset_relay_chain();
+		KusamaNet::execute_with(|| {
+			assert_eq!(kusama_runtime::System::block_number(), 1);
+			assert!(Paras::<kusama_runtime::Runtime>::is_valid_para(2.into()));
+		});
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+	}
+
+	#[test]
+	fn checkpoints() {
+		Network::reset();
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+		let mushroom_block = YayoiMushroom::execute_with(yayoi::System::block_number);
+		Network::save_checkpoint("remarked");
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!()]);
+
+		Network::restore_checkpoint("remarked");
+		YayoiMushroom::execute_with(|| assert_eq!(yayoi::System::block_number(), mushroom_block));
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+
+		// a checkpoint can be restored again, and survives a reset
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		Network::reset();
+		Network::restore_checkpoint("remarked");
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+	}
+
+	#[test]
+	fn reset_restores_default_modes() {
+		Network::reset();
+		Network::set_strict_mode(true);
+		Network::set_message_delivery(MessageDelivery::Inherent);
+		Network::set_hrmp_routing(HrmpRouting::Relay);
+		Network::reset();
+
+		// the message fails on Mushroom without failing the test, and is handed to it directly
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![Trap(42)]),
+			));
+		});
+		assert!(KusamaNet::hrmp_channel_contents(1.into(), 2.into()).is_empty());
+		let horizontal = Network::messages(MessageFilter::default().kind(MessageKind::Horizontal));
+		assert_eq!(horizontal[0].error(), Some(XcmError::Trap(42)));
+	}
+
+	#[test]
+	#[should_panic(expected = "no checkpoint")]
+	fn restore_unknown_checkpoint() {
+		Network::reset();
+		Network::restore_checkpoint("unknown");
+	}
+
+	#[test]
+	fn message_journal() {
+		Network::reset();
+
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+		kusama_send_rmrk("Kusama", 1);
+
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		let sibling_remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+		});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				Parent,
+				Xcm(vec![
+					UnpaidExecution {
+						weight_limit: Unlimited,
+						check_origin: None,
+					},
+					Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(1_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}
+				]),
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 20_000_000.into(),
+					call: sibling_remark.encode().into(),
+				}]),
+			));
+		});
+
+		let messages = Network::messages(MessageFilter::default());
+		assert_eq!(messages.len(), 3);
+
+		let downward = Network::messages(MessageFilter::default().kind(MessageKind::Downward));
+		assert_eq!(downward.len(), 1);
+		assert_eq!(downward[0].sender, ChainId::Relay);
+		assert_eq!(downward[0].recipient, ChainId::Parachain(1));
+		assert_eq!(downward[0].status(), MessageStatus::Complete);
+		assert!(matches!(downward[0].xcm(), Some(VersionedXcm::V3(_))));
+
+		// the relay chain doesn't allow the sovereign account of Pumpkin to remark
+		let upward = Network::messages(
+			MessageFilter::default()
+				.sender(ChainId::Parachain(1))
+				.recipient(ChainId::Relay),
+		);
+		assert_eq!(upward.len(), 1);
+		assert_eq!(upward[0].kind, MessageKind::Upward);
+		assert!(matches!(upward[0].outcome, Some(Outcome::Incomplete(_, XcmError::NoPermission))));
+		assert_eq!(upward[0].error(), Some(XcmError::NoPermission));
+		assert_eq!(Network::messages(MessageFilter::default().status(MessageStatus::Failed)), upward);
+
+		let horizontal = Network::messages(MessageFilter::default().recipient(ChainId::Parachain(2)));
+		assert_eq!(horizontal.len(), 1);
+		assert_eq!(horizontal[0].kind, MessageKind::Horizontal);
+		assert_eq!(horizontal[0].status(), MessageStatus::Complete);
+		assert!(horizontal[0].weight_used().unwrap().any_gt(Weight::zero()));
+
+		Network::reset();
+		assert!(Network::messages(MessageFilter::default()).is_empty());
+	}
+
+	#[test]
+	fn xcmp_channel_back_pressure() {
+		Network::reset();
+
+		// only two of these messages fit in one page of `HRMP_CHANNEL_CONFIG.max_message_size`
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: vec![0u8; 400],
+		});
+		YayoiPumpkin::execute_with(|| {
+			for _ in 0..3 {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					MultiLocation::new(1, X1(Parachain(2))),
+					Xcm(vec![Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: 20_000_000.into(),
+						call: remark.encode().into(),
+					}]),
+				));
+			}
+		});
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!()]);
+		assert_eq!(Network::hrmp_channel_usage(1, 2), (0, 0));
+
+		// the last page stays in Pumpkin's outbound queue until its next block
+		YayoiPumpkin::execute_with(|| {});
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!(), remarked!()]);
+	}
+
+	#[test]
+	fn xcmp_channel_limits() {
+		Network::reset();
+		// Mushroom only consumes messages with its own blocks
+		Network::set_message_delivery(MessageDelivery::Inherent);
+
+		let send_remark = |size: usize| {
+			let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+				remark: vec![0u8; size],
+			});
+			YayoiOctopus::execute_with(|| {
+				assert_ok!(yayoi::PolkadotXcm::send_xcm(
+					Here,
+					MultiLocation::new(1, X1(Parachain(2))),
+					Xcm(vec![Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: 20_000_000.into(),
+						call: remark.encode().into(),
+					}]),
+				));
+			});
+		};
+		let sent_count = || {
+			Network::messages(
+				MessageFilter::default()
+					.kind(MessageKind::Horizontal)
+					.sender(ChainId::Parachain(3)),
+			)
+			.len()
+		};
+
+		// a page per block until `max_capacity` pages fill the channel, the next page stays in the
+		// outbound queue of Octopus
+		for _ in 0..3 {
+			send_remark(10);
+		}
+		assert_eq!(sent_count(), 2);
+		assert_eq!(Network::hrmp_channel_usage(3, 2).0, SMALL_HRMP_CHANNEL_CONFIG.max_capacity);
+
+		// Mushroom consumes the pages with its next block, then Octopus sends the last one
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!()]);
+		assert_eq!(Network::hrmp_channel_usage(3, 2), (0, 0));
+		YayoiOctopus::execute_with(|| {});
+		assert_eq!(sent_count(), 3);
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!(), remarked!()]);
+
+		// one large page takes most of `max_total_size`, the next one doesn't fit
+		send_remark(600);
+		send_remark(600);
+		assert_eq!(sent_count(), 4);
+		let (msg_count, total_size) = Network::hrmp_channel_usage(3, 2);
+		assert_eq!(msg_count, 1);
+		assert!(total_size > SMALL_HRMP_CHANNEL_CONFIG.max_total_size / 2);
+
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!(), remarked!(), remarked!()]);
+		YayoiOctopus::execute_with(|| {});
+		assert_eq!(sent_count(), 5);
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!(), remarked!(), remarked!(), remarked!(), remarked!()]);
+	}
+
+	#[test]
+	fn xcmp_through_a_parachain() {
+		use yayoi::{PolkadotXcm, Runtime, RuntimeCall};
+
+		Network::reset();
+
+		// The message goes through: Pumpkin --> Mushroom --> Octopus
+		let remark = RuntimeCall::System(frame_system::Call::<Runtime>::remark_with_event {
+			remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+		});
+		let send_xcm_to_octopus = RuntimeCall::PolkadotXcm(pallet_xcm::Call::<Runtime>::send {
+			dest: Box::new(VersionedMultiLocation::V3(MultiLocation::new(1, X1(Parachain(3))))),
+			message: Box::new(VersionedXcm::V3(Xcm(vec![Transact {
+				origin_kind: OriginKind::SovereignAccount,
+				require_weight_at_most: 10_000_000.into(),
+				call: remark.encode().into(),
+			}]))),
+		});
+		assert_eq!(
+			send_xcm_to_octopus.get_dispatch_info().weight,
+			Weight::from_parts(110000010, 10000010)
+		);
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 110_000_010.into(),
+					call: send_xcm_to_octopus.encode().into(),
+				}]),
+			));
+		});
+
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, [yayoi::RuntimeEvent::PolkadotXcm(pallet_xcm::Event::Sent(..))]);
+
+		// execution would fail, but good enough to check if the message is received
+		YayoiOctopus::execute_with(|| {});
+		assert_expected_events!(
+			YayoiOctopus,
+			[yayoi::RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Fail { .. })]
+		);
+
+		let to_mushroom = Network::messages(MessageFilter::default().recipient(ChainId::Parachain(2)));
+		assert_eq!(to_mushroom.len(), 1);
+		assert_eq!(to_mushroom[0].status(), MessageStatus::Complete);
+		let to_octopus = Network::messages(MessageFilter::default().recipient(ChainId::Parachain(3)));
+		assert_eq!(to_octopus.len(), 1);
+		assert_eq!(to_octopus[0].sender, ChainId::Parachain(2));
+		assert_eq!(to_octopus[0].status(), MessageStatus::Failed);
+	}
+
+	#[test]
+	#[should_panic(expected = "Upward message from YayoiPumpkin to KusamaNet failed with NoPermission")]
+	fn strict_mode() {
+		Network::reset();
+		Network::set_strict_mode(true);
+
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				Parent,
+				Xcm(vec![
+					UnpaidExecution {
+						weight_limit: Unlimited,
+						check_origin: None,
+					},
+					Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(1_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}
+				]),
+			));
+		});
+	}
+
+	#[test]
+	#[should_panic(expected = "Upward message from YayoiPumpkin to KusamaNet failed with WeightLimitReached")]
+	fn strict_mode_overweight() {
+		Network::reset();
+		Network::set_strict_mode(true);
+
+		// exceeds `ump_max_individual_weight` of the relay chain
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				Parent,
+				Xcm(vec![
+					UnpaidExecution {
+						weight_limit: Unlimited,
+						check_origin: None,
+					},
+					Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(25_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}
+				]),
+			));
+		});
+	}
+
+	#[test]
+	fn message_trace() {
+		use yayoi::{PolkadotXcm, Runtime, RuntimeCall};
+
+		Network::reset();
+
+		// Pumpkin --> Mushroom --> Octopus, the remark fails on Octopus
+		let remark = RuntimeCall::System(frame_system::Call::<Runtime>::remark_with_event {
+			remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+		});
+		let send_xcm_to_octopus = RuntimeCall::PolkadotXcm(pallet_xcm::Call::<Runtime>::send {
+			dest: Box::new(VersionedMultiLocation::V3(MultiLocation::new(1, X1(Parachain(3))))),
+			message: Box::new(VersionedXcm::V3(Xcm(vec![Transact {
+				origin_kind: OriginKind::SovereignAccount,
+				require_weight_at_most: 10_000_000.into(),
+				call: remark.encode().into(),
+			}]))),
+		});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 110_000_010.into(),
+					call: send_xcm_to_octopus.encode().into(),
+				}]),
+			));
+		});
+
+		let trace = Network::trace();
+		assert_eq!(trace.roots.len(), 1);
+		let to_mushroom = &trace.roots[0];
+		assert_eq!((to_mushroom.sender_name, to_mushroom.recipient_name), ("YayoiPumpkin", "YayoiMushroom"));
+		assert_eq!(to_mushroom.entry.status(), MessageStatus::Complete);
+
+		assert_eq!(to_mushroom.children.len(), 1);
+		let to_octopus = &to_mushroom.children[0];
+		assert_eq!((to_octopus.sender_name, to_octopus.recipient_name), ("YayoiMushroom", "YayoiOctopus"));
+		assert_eq!(to_octopus.entry.status(), MessageStatus::Failed);
+		assert!(to_octopus.children.is_empty());
+
+		let rendered = trace.to_string();
+		assert!(rendered.starts_with("YayoiPumpkin -> YayoiMushroom (Horizontal, relay block "));
+		assert!(rendered.contains("\n  YayoiMushroom -> YayoiOctopus (Horizontal, relay block "));
+		assert!(rendered.contains("\n    | Transact {"));
+	}
+
+	#[test]
+	fn xcmp_page() {
+		let clear_origin = VersionedXcm::<()>::V3(Xcm(vec![ClearOrigin]));
+		let trap = VersionedXcm::<()>::V3(Xcm(vec![Trap(1)]));
+		let mut page = XcmpMessageFormat::ConcatenatedVersionedXcm.encode();
+		page.extend(clear_origin.encode());
+		page.extend(trap.encode());
+
+		let decoded = XcmpPage::decode(&page).unwrap();
+		assert_eq!(decoded.format, XcmpMessageFormat::ConcatenatedVersionedXcm);
+		assert_eq!(decoded.xcms().collect::<Vec<_>>(), vec![&clear_origin, &trap]);
+		assert_eq!(
+			decoded.items[1],
+			XcmpPageItem::Xcm {
+				encoded: trap.encode(),
+				xcm: trap.clone()
+			}
+		);
+
+		let mut signals = XcmpMessageFormat::Signals.encode();
+		signals.extend(ChannelSignal::Suspend.encode());
+		signals.extend(ChannelSignal::Resume.encode());
+		assert_eq!(
+			XcmpPage::decode(&signals).unwrap().items,
+			vec![XcmpPageItem::Signal(ChannelSignal::Suspend), XcmpPageItem::Signal(ChannelSignal::Resume)]
+		);
+
+		// a truncated XCM
+		let truncated = &page[..page.len() - 1];
+		assert!(XcmpPage::decode(truncated).is_err());
+
+		// the XCMs before it still decode
+		let (decoded, rest) = XcmpPage::decode_prefix(truncated).unwrap();
+		assert_eq!(decoded.xcms().collect::<Vec<_>>(), vec![&clear_origin]);
+		assert_eq!(rest, &trap.encode()[..trap.encode().len() - 1]);
+		assert!(XcmpPage::decode_prefix(&[]).is_err());
+	}
+
+	#[test]
+	fn bridged_xcm() {
+		Network::reset();
+		polkadot::PolkadotNetwork::reset();
+		assert_eq!(KusamaNet::global_consensus(), Some(NetworkId::Kusama));
+		assert_eq!(polkadot::PolkadotNet::global_consensus(), Some(NetworkId::Polkadot));
+
+		// Mushroom is the bridge hub of Kusama, exporting the message to the Polkadot bridge hub
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![ExportMessage {
+					network: NetworkId::Polkadot,
+					destination: X1(Parachain(1)),
+					xcm: Xcm(vec![Trap(42)]),
+				}]),
+			));
+		});
+
+		// the message is received with the origin of Pumpkin in Kusama, and fails at `Trap`
+		polkadot::PolkadotPumpkin::execute_with(|| {});
+		assert_expected_events!(
+			polkadot::PolkadotPumpkin,
+			[yayoi::RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Fail {
+				error: XcmError::Trap(42),
+				..
+			})]
+		);
+		assert!(polkadot::PolkadotNetwork::relayed_bridge_messages()[0].result.is_ok());
+		assert!(Network::relayed_bridge_messages().is_empty());
+		let bridged = polkadot::PolkadotNetwork::messages(MessageFilter::default().sender(ChainId::Parachain(2)));
+		assert_eq!(bridged.len(), 1);
+		assert_eq!(bridged[0].recipient, ChainId::Parachain(1));
+		assert_eq!(bridged[0].error(), Some(XcmError::Trap(42)));
+	}
+
+	#[test]
+	fn bridged_xcm_dispatch_failure() {
+		Network::reset();
+		polkadot::PolkadotNetwork::reset();
+
+		// the Polkadot bridge hub has no channel to parachain 9
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![ExportMessage {
+					network: NetworkId::Polkadot,
+					destination: X1(Parachain(9)),
+					xcm: Xcm(vec![Trap(42)]),
+				}]),
+			));
+		});
+
+		let relayed = polkadot::PolkadotNetwork::relayed_bridge_messages();
+		assert_eq!(relayed.len(), 1);
+		assert_eq!(relayed[0].message.destination, X1(Parachain(9)));
+		assert_eq!(relayed[0].result, Err(SendError::Transport("NoChannel")));
+
+		// a reset of the network clears the messages relayed to it
+		polkadot::PolkadotNetwork::reset();
+		assert!(polkadot::PolkadotNetwork::relayed_bridge_messages().is_empty());
+	}
+
+	#[test]
+	fn bridged_messages_in_checkpoints() {
+		Network::reset();
+		polkadot::PolkadotNetwork::reset();
+		polkadot::PolkadotNetwork::save_checkpoint("not bridged");
+
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![ExportMessage {
+					network: NetworkId::Polkadot,
+					destination: X1(Parachain(1)),
+					xcm: Xcm(vec![Trap(42)]),
+				}]),
+			));
+		});
+		assert_eq!(polkadot::PolkadotNetwork::relayed_bridge_messages().len(), 1);
+		polkadot::PolkadotNetwork::save_checkpoint("bridged");
+
+		// the messages relayed to Polkadot go back with its checkpoints
+		polkadot::PolkadotNetwork::restore_checkpoint("not bridged");
+		assert!(polkadot::PolkadotNetwork::relayed_bridge_messages().is_empty());
+		polkadot::PolkadotNetwork::restore_checkpoint("bridged");
+		assert_eq!(polkadot::PolkadotNetwork::relayed_bridge_messages().len(), 1);
+	}
+
+	#[test]
+	fn bridged_origin_only_from_bridge_hub() {
+		Network::reset();
+
+		// Pumpkin isn't the bridge hub, so can't alias an origin of Polkadot
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![UniversalOrigin(GlobalConsensus(NetworkId::Polkadot)), Trap(42)]),
+			));
+		});
+
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(
+			YayoiMushroom,
+			[yayoi::RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Fail {
+				error: XcmError::InvalidLocation,
+				..
+			})]
+		);
+	}
+
+	#[test]
+	fn repeated_dmp() {
+		use polkadot_primitives::runtime_api::runtime_decl_for_parachain_host::ParachainHostV4;
+
+		Network::reset();
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		// same dmp messages in same relay-parent-block are all executed
+		kusama_send_rmrk("Kusama", 2);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!(), remarked!()]);
+		parachain_reset_events();
+
+		kusama_send_rmrk("Polkadot", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+
+		kusama_send_rmrk("Kusama", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+
+		// processed messages are pruned from the relay chain
+		assert_eq!(Network::processed_downward_messages(1), 4);
+		KusamaNet::execute_with(|| assert!(kusama_runtime::Runtime::dmq_contents(1.into()).is_empty()));
+
+		// reset can send same dmp message again
+		Network::reset();
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		kusama_send_rmrk("Kusama", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+	}
+
+	#[test]
+	fn inherent_delivery() {
+		use polkadot_primitives::runtime_api::runtime_decl_for_parachain_host::ParachainHostV4;
+
+		Network::reset();
+		Network::set_message_delivery(MessageDelivery::Inherent);
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		// downward messages are delivered in the next block of the recipient
+		kusama_send_rmrk("Kusama", 2);
+		assert_eq!(Network::processed_downward_messages(1), 0);
+		// and stay in the relay chain until then
+		KusamaNet::execute_with(|| assert_eq!(kusama_runtime::Runtime::dmq_contents(1.into()).len(), 2));
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!(), remarked!()]);
+		parachain_reset_events();
+		assert_eq!(Network::processed_downward_messages(1), 2);
+		KusamaNet::execute_with(|| assert!(kusama_runtime::Runtime::dmq_contents(1.into()).is_empty()));
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+
+		// horizontal messages occupy the channel until the recipient receives them
+		assert_eq!(Network::hrmp_channel_usage(1, 2).0, 1);
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+		assert_eq!(Network::hrmp_channel_usage(1, 2), (0, 0));
+
+		// MQC heads stay consistent across blocks
+		Network::produce_blocks(2);
+		kusama_send_rmrk("Kusama", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+	}
+
+	#[test]
+	fn on_demand_parachain() {
+		Network::reset();
+		Network::set_on_demand(2, true);
+
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: "Hello!".as_bytes().to_vec(),
+		});
+		let send_remark = |dest: u32| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(dest))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 20_000_000.into(),
+					call: remark.encode().into(),
+				}]),
+			));
+		};
+
+		// messages to Mushroom queue up until it's ordered a block
+		YayoiPumpkin::execute_with(|| send_remark(2));
+		YayoiMushroom::execute_with(|| {});
+		assert_no_events!(YayoiMushroom, [remarked!()]);
+		Network::produce_blocks(1);
+		YayoiMushroom::execute_with(|| {});
+		assert_no_events!(YayoiMushroom, [remarked!()]);
+		assert_eq!(Network::hrmp_channel_usage(1, 2).0, 1);
+
+		Network::place_order(2);
+		assert_eq!(Network::pending_orders(2), 1);
+		Network::produce_blocks(1);
+		assert_eq!(Network::pending_orders(2), 0);
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+		assert_eq!(Network::hrmp_channel_usage(1, 2), (0, 0));
+
+		// messages from Mushroom leave with its next block
+		YayoiMushroom::execute_with(|| send_remark(1));
+		YayoiPumpkin::execute_with(|| {});
+		assert_no_events!(YayoiPumpkin, [remarked!()]);
+		YayoiMushroom::produce_block();
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+	}
+
+	#[test]
+	fn async_backing() {
+		Network::reset();
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+		YayoiPumpkin::execute_with(|| yayoi::AsyncBacking::set(&true));
+		Network::set_async_backing(Some(AsyncBackingParams {
+			lookahead: 2,
+			max_blocks_per_relay_parent: 2,
+		}));
+
+		// several blocks are built on the same relay parent
+		let relay_parent = KusamaNet::block_number();
+		YayoiPumpkin::produce_block();
+		YayoiPumpkin::produce_block();
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_parent);
+		YayoiPumpkin::produce_block();
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_parent + 1);
+		assert_eq!(KusamaNet::block_number(), relay_parent + 1);
+
+		// messages wait for a block on a relay parent not older than the relay chain block they were
+		// sent in
+		KusamaNet::produce_block();
+		KusamaNet::produce_block();
+		Network::set_relay_parent_lag(1, 1);
+		kusama_send_rmrk("Kusama", 1);
+		let processed = Network::processed_downward_messages(1);
+
+		YayoiPumpkin::produce_block();
+		YayoiPumpkin::produce_block();
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_parent + 2);
+		assert_eq!(Network::processed_downward_messages(1), processed);
+
+		YayoiPumpkin::produce_block();
+		assert_eq!(YayoiPumpkin::last_relay_parent_number(), relay_parent + 3);
+		assert_eq!(Network::processed_downward_messages(1), processed + 1);
+	}
+
+	#[test]
+	#[should_panic(expected = "strictly increase")]
+	fn async_backing_with_strictly_increasing_relay_parents() {
+		Network::reset();
+		Network::set_async_backing(Some(AsyncBackingParams {
+			lookahead: 2,
+			max_blocks_per_relay_parent: 2,
+		}));
+
+		YayoiPumpkin::produce_block();
+		YayoiPumpkin::produce_block();
+	}
+
+	#[test]
+	fn hrmp_through_relay_chain() {
+		Network::reset();
+		Network::set_hrmp_routing(HrmpRouting::Relay);
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+
+		// the message waits in the relay chain `hrmp` pallet
+		let contents = KusamaNet::hrmp_channel_contents(1.into(), 2.into());
+		assert_eq!(contents.len(), 1);
+		assert_eq!(contents[0].sent_at, KusamaNet::block_number());
+		assert_eq!(Network::hrmp_channel_usage(1, 2).0, 1);
+
+		// Mushroom reads it in its next block, advancing its watermark
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+		assert!(KusamaNet::hrmp_channel_contents(1.into(), 2.into()).is_empty());
+		assert_eq!(KusamaNet::hrmp_watermark(2.into()), Some(KusamaNet::block_number()));
+		assert_eq!(Network::hrmp_channel_usage(1, 2), (0, 0));
+
+		// channels of offboarded parachains are closed, and left out of the relay chain state proof
+		Network::deregister_parachain(2);
+		assert_eq!(KusamaNet::hrmp_watermark(2.into()), None);
+		assert_eq!(KusamaNet::hrmp_channels(1.into()).0, vec![]);
+		YayoiPumpkin::execute_with(|| {
+			assert_eq!(
+				yayoi::PolkadotXcm::send_xcm(Here, MultiLocation::new(1, X1(Parachain(2))), Xcm(vec![ClearOrigin])),
+				Err(SendError::Transport("NoChannel"))
+			);
+		});
+	}
+
+	#[test]
+	fn hrmp_routing_back_to_direct() {
+		Network::reset();
+		Network::set_hrmp_routing(HrmpRouting::Relay);
+
+		pumpkin_send_rmrk("Hello from Pumpkin!");
+		assert_eq!(KusamaNet::hrmp_channel_contents(1.into(), 2.into()).len(), 1);
+
+		// the message committed into the relay chain is handed to Mushroom directly
+		Network::set_hrmp_routing(HrmpRouting::Direct);
+		assert!(KusamaNet::hrmp_channel_contents(1.into(), 2.into()).is_empty());
+		YayoiMushroom::execute_with(|| {});
+		assert_expected_events!(YayoiMushroom, only [remarked!()]);
+	}
+
+	#[test]
+	fn parachain_runtime_upgrade() {
+		use polkadot_primitives::UpgradeRestriction;
+
+		Network::reset();
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		// the relay chain schedules the upgrade with the block of the parachain setting it
+		let code = b"Pumpkin v2".to_vec();
+		assert_ok!(YayoiPumpkin::schedule_runtime_upgrade(code.clone()));
+		assert_eq!(
+			KusamaNet::upgrade_signals(1.into()),
+			(None, Some(UpgradeRestriction::Present))
+		);
+		YayoiPumpkin::execute_with(|| assert_ne!(unhashed::get_raw(b":code"), Some(code.clone())));
+
+		// and signals the go-ahead once `validation_upgrade_delay` is over
+		Network::produce_blocks(12);
+		assert_eq!(KusamaNet::upgrade_signals(1.into()).0, None);
+		KusamaNet::execute_with(|| {
+			assert_eq!(
+				kusama_runtime::Paras::current_code_hash(ParaId::from(1)),
+				Some(ValidationCode(code.clone()).hash())
+			);
+		});
+		YayoiPumpkin::execute_with(|| {
+			assert_eq!(unhashed::get_raw(b":code"), Some(code.clone()));
+			assert_eq!(unhashed::get::<u32>(PUMPKIN_UPGRADES), Some(1));
+		});
+
+		// messages pass after the upgrade
+		kusama_send_rmrk("Kusama", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+	}
+
+	#[test]
+	fn rejected_parachain_code_upgrade() {
+		use polkadot_primitives::UpgradeRestriction;
+
+		Network::reset();
+
+		YayoiPumpkin::execute_with(|| {
+			// the relay chain restricts upgrades of Pumpkin after the block was built on it
+			KusamaNet::set_upgrade_restriction(1.into(), Some(UpgradeRestriction::Present));
+			assert_ok!(yayoi::System::set_code_without_checks(
+				yayoi::RuntimeOrigin::root(),
+				b"Pumpkin v2".to_vec()
+			));
+		});
+
+		let rejected = Network::rejected_code_upgrades();
+		assert_eq!(rejected.len(), 1);
+		assert_eq!(rejected[0].para_id, 1);
+		assert_eq!(
+			rejected[0].error,
+			polkadot_runtime_parachains::inclusion::Error::<kusama_runtime::Runtime>::PrematureCodeUpgrade.into()
+		);
+		assert_eq!(KusamaNet::upgrade_signals(1.into()).0, None);
+	}
+
+	#[test]
+	fn relay_chain_runtime_upgrade() {
+		Network::reset();
+
+		let code = b"Kusama v2".to_vec();
+		KusamaNet::upgrade_runtime(code.clone());
+		assert_expected_events!(KusamaNet, [kusama_runtime::RuntimeEvent::System(frame_system::Event::CodeUpdated)]);
+		KusamaNet::execute_with(|| {
+			assert_eq!(unhashed::get_raw(b":code"), Some(code));
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		kusama_send_rmrk("Kusama", 1);
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		parachain_reset_events();
+	}
+
+	#[test]
+	fn overweight_dmp() {
+		Network::reset();
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::force_default_xcm_version(
+				kusama_runtime::RuntimeOrigin::root(),
+				Some(3)
+			));
+		});
+
+		// exceeds `max_individual` of the dmp queue
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: "Hello from Kusama!".as_bytes().to_vec(),
+		});
+		KusamaNet::execute_with(|| {
+			assert_ok!(kusama_runtime::XcmPallet::send_xcm(
+				Here,
+				Parachain(1),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: Weight::from_parts(20_000_000_000, 16 * 1024),
+					call: remark.encode().into(),
+				}]),
+			));
+		});
+		YayoiPumpkin::execute_with(|| {});
+		assert_no_events!(YayoiPumpkin, [remarked!()]);
+
+		let overweight = Network::overweight_messages();
+		assert_eq!(overweight.len(), 1);
+		assert_eq!(overweight[0].queue, OverweightQueue::Downward(1));
+		assert_eq!(overweight[0].origin, MultiLocation::parent());
+		assert!(matches!(overweight[0].xcm(), Some(VersionedXcm::V3(_))));
+
+		assert_ok!(Network::service_overweight(
+			overweight[0].queue,
+			overweight[0].index,
+			Weight::from_parts(30_000_000_000, 64 * 1024)
+		));
+		YayoiPumpkin::execute_with(|| {});
+		assert_expected_events!(YayoiPumpkin, only [remarked!()]);
+		assert!(Network::overweight_messages().is_empty());
+	}
+
+	#[test]
+	fn overweight_ump() {
+		Network::reset();
+
+		// exceeds `ump_max_individual_weight` of the relay chain
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				Parent,
+				Xcm(vec![
+					UnpaidExecution {
+						weight_limit: Unlimited,
+						check_origin: None,
+					},
+					Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(25_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}
+				]),
+			));
+		});
+
+		// the message is not deferred forever, and recorded as failed
+		assert!(Network::deferred_upward_messages().is_empty());
+		let failed = Network::messages(MessageFilter::default().kind(MessageKind::Upward));
+		assert_eq!(failed.len(), 1);
+		assert!(matches!(failed[0].error(), Some(XcmError::WeightLimitReached(_))));
+		let overweight = Network::overweight_messages();
+		assert_eq!(overweight.len(), 1);
+		assert_eq!(overweight[0].queue, OverweightQueue::Upward);
+		assert_eq!(overweight[0].origin, Parachain(1).into());
+
+		assert_ok!(Network::service_overweight(
+			OverweightQueue::Upward,
+			overweight[0].index,
+			Weight::from_parts(30_000_000_000, 64 * 1024)
+		));
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(
+			KusamaNet,
+			[kusama_runtime::RuntimeEvent::Ump(polkadot_runtime_parachains::ump::Event::OverweightServiced(..))]
+		);
+		assert!(Network::overweight_messages().is_empty());
+	}
+
+	#[test]
+	fn ump_between_total_and_individual_weight() {
+		Network::reset();
+
+		// exceeds `ump_service_total_weight`, but not `ump_max_individual_weight` of the relay chain
+		let remark =
+			kusama_runtime::RuntimeCall::System(frame_system::Call::<kusama_runtime::Runtime>::remark_with_event {
+				remark: "Hello from Pumpkin!".as_bytes().to_vec(),
+			});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::force_default_xcm_version(
+				yayoi::RuntimeOrigin::root(),
+				Some(3)
+			));
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				Parent,
+				Xcm(vec![
+					UnpaidExecution {
+						weight_limit: Unlimited,
+						check_origin: None,
+					},
+					Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(10_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}
+				]),
+			));
+		});
+
+		// the message is executed, neither deferred nor kept as overweight
+		assert!(Network::deferred_upward_messages().is_empty());
+		assert!(Network::overweight_messages().is_empty());
+		KusamaNet::execute_with(|| {});
+		assert_expected_events!(KusamaNet, only [executed_upward!()]);
+	}
+
+	fn kusama_send_rmrk(msg: &str, count: u32) {
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: msg.as_bytes().to_vec(),
+		});
+		KusamaNet::execute_with(|| {
+			for _ in 0..count {
+				assert_ok!(kusama_runtime::XcmPallet::send_xcm(
+					Here,
+					Parachain(1),
+					Xcm(vec![Transact {
+						origin_kind: OriginKind::SovereignAccount,
+						require_weight_at_most: Weight::from_parts(1_000_000_000, 16 * 1024),
+						call: remark.encode().into(),
+					}]),
+				));
+			}
+		});
+	}
+
+	fn pumpkin_send_rmrk(msg: &str) {
+		let remark = yayoi::RuntimeCall::System(frame_system::Call::<yayoi::Runtime>::remark_with_event {
+			remark: msg.as_bytes().to_vec(),
+		});
+		YayoiPumpkin::execute_with(|| {
+			assert_ok!(yayoi::PolkadotXcm::send_xcm(
+				Here,
+				MultiLocation::new(1, X1(Parachain(2))),
+				Xcm(vec![Transact {
+					origin_kind: OriginKind::SovereignAccount,
+					require_weight_at_most: 20_000_000.into(),
+					call: remark.encode().into(),
+				}]),
+			));
+		});
+	}
+
+	fn parachain_reset_events() {
+		YayoiPumpkin::execute_with(yayoi::System::reset_events);
+
+		let pending = MessageFilter::default().recipient(ChainId::Parachain(1)).status(MessageStatus::Pending);
+		assert!(Network::messages(pending).is_empty());
 	}
 }
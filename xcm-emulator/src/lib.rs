@@ -2,32 +2,58 @@ pub use codec::Encode;
 pub use paste;
 
 pub use frame_support::{
+	self,
 	traits::{Get, Hooks},
 	weights::Weight,
 };
 pub use frame_system;
-pub use sp_arithmetic::traits::Bounded;
-pub use sp_io::TestExternalities;
-pub use sp_std::{cell::RefCell, collections::vec_deque::VecDeque, marker::PhantomData};
+pub use sp_arithmetic::traits::{Bounded, One};
+pub use sp_io::{hashing::blake2_256, TestExternalities};
+pub use sp_std::{
+	cell::RefCell,
+	collections::{btree_map::BTreeMap, btree_set::BTreeSet, vec_deque::VecDeque},
+	marker::PhantomData,
+};
 
 pub use cumulus_pallet_dmp_queue;
-pub use cumulus_pallet_parachain_system;
-pub use cumulus_pallet_xcmp_queue;
+pub use cumulus_pallet_parachain_system::{self, MessageQueueChain};
+pub use cumulus_pallet_xcmp_queue::{self, ChannelSignal};
 pub use cumulus_primitives_core::{
-	self, relay_chain::BlockNumber as RelayBlockNumber, DmpMessageHandler, ParaId, PersistedValidationData,
-	XcmpMessageHandler,
+	self,
+	relay_chain::{BlockNumber as RelayBlockNumber, Hash as RelayHash},
+	AbridgedHostConfiguration, DmpMessageHandler, InboundDownwardMessage, InboundHrmpMessage, ParaId,
+	PersistedValidationData, XcmpMessageFormat, XcmpMessageHandler,
 };
 pub use cumulus_primitives_parachain_inherent::ParachainInherentData;
 pub use cumulus_test_relay_sproof_builder::RelayStateSproofBuilder;
+pub use pallet_xcm;
 pub use parachain_info;
 
+pub use polkadot_parachain::primitives::Sibling;
 pub use polkadot_primitives;
 pub use polkadot_runtime_parachains::{
+	configuration::{self, HostConfiguration},
 	dmp,
-	ump::{MessageId, UmpSink, XcmSink},
+	hrmp::{self, HrmpChannel},
+	inclusion, paras,
+	ump::{self, MessageId, UmpSink, XcmSink},
 };
 pub use xcm::{v3::prelude::*, VersionedXcm};
-pub use xcm_executor::XcmExecutor;
+pub use xcm_builder::{
+	AccountId32Aliases, ChildParachainConvertsVia, ParentIsPreset, SiblingParachainConvertsVia,
+};
+pub use xcm_executor::{
+	self,
+	traits::{Convert, ExportXcm},
+	XcmExecutor,
+};
+
+use codec::{Decode, DecodeLimit};
+use cumulus_primitives_core::relay_chain::HrmpChannelId;
+use frame_support::{
+	storage::migration::{get_storage_value, put_storage_value, storage_key_iter, take_storage_value},
+	Blake2_128Concat, StorageHasher, Twox64Concat,
+};
 
 pub trait TestExt {
 	fn new_ext() -> sp_io::TestExternalities;
@@ -35,6 +61,1226 @@ pub trait TestExt {
 	fn execute_with<R>(execute: impl FnOnce() -> R) -> R;
 }
 
+/// Account id type of the runtime `T`.
+pub type AccountIdOf<T> = <T as frame_system::Config>::AccountId;
+
+/// A chain of an emulated network.
+pub trait Chain: TestExt {
+	type Runtime: frame_system::Config;
+	type RuntimeCall;
+	type RuntimeOrigin;
+	type RuntimeEvent;
+
+	/// Events deposited in the current block of the chain.
+	fn events() -> Vec<Self::RuntimeEvent>;
+
+	/// Account of `location` on the chain, as converted by its `LocationToAccountId`. Panics if
+	/// `location` has no account.
+	///
+	/// Chains declared without `LocationToAccountId` convert locations with the default converters
+	/// of `xcm_builder`, ending with `AccountId32Aliases`, which need an `AccountId` convertible from
+	/// and into `[u8; 32]`, as `AccountId32` is. Chains of other account ids must declare their own.
+	fn sovereign_account_id_of(location: MultiLocation) -> AccountIdOf<Self::Runtime>;
+}
+
+/// A relay chain of an emulated network.
+pub trait RelayChain: Chain {
+	/// Sovereign account of the parachain `para_id` on the relay chain.
+	fn sovereign_account_id_of_child_para(para_id: ParaId) -> AccountIdOf<Self::Runtime> {
+		Self::sovereign_account_id_of(Junction::Parachain(para_id.into()).into())
+	}
+}
+
+/// A parachain of an emulated network.
+pub trait Parachain: Chain {
+	/// Para id of the parachain.
+	fn para_id() -> ParaId;
+
+	/// Sovereign account of the sibling parachain `para_id` on the parachain.
+	fn sovereign_account_id_of_sibling(para_id: ParaId) -> AccountIdOf<Self::Runtime> {
+		Self::sovereign_account_id_of(MultiLocation::new(1, X1(Junction::Parachain(para_id.into()))))
+	}
+}
+
+/// Limits of an open HRMP channel declared in `decl_test_network!`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HrmpChannelConfig {
+	/// Max number of messages the channel can hold.
+	pub max_capacity: u32,
+	/// Max total size in bytes of all messages in the channel.
+	pub max_total_size: u32,
+	/// Max size in bytes of a single message.
+	pub max_message_size: u32,
+}
+
+impl Default for HrmpChannelConfig {
+	fn default() -> Self {
+		Self {
+			max_capacity: 1024,
+			max_total_size: 1024 * 1024,
+			max_message_size: 1024 * 1024,
+		}
+	}
+}
+
+impl HrmpChannelConfig {
+	/// Channel limits a relay chain with `config` would accept when opening a new channel.
+	pub fn from_host_configuration(config: &HostConfiguration<RelayBlockNumber>) -> Self {
+		Self {
+			max_capacity: config.hrmp_channel_max_capacity,
+			max_total_size: config.hrmp_channel_max_total_size,
+			max_message_size: config.hrmp_channel_max_message_size,
+		}
+	}
+}
+
+/// How the network delivers downward and horizontal messages to parachains.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageDelivery {
+	/// Call `DmpMessageHandler` and `XcmpMessageHandler` of the recipient as soon as messages are sent.
+	#[default]
+	Direct,
+	/// Put messages into the `ParachainInherentData` of the next block of the recipient, so they are
+	/// delivered by `set_validation_data` with MQC checks and weight reservation as in production.
+	Inherent,
+}
+
+/// Async backing parameters of a network, under which parachains may build several blocks on the
+/// same relay parent, and build on relay parents older than the relay chain head.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsyncBackingParams {
+	/// Max number of relay chain blocks a relay parent may lag behind the relay chain head.
+	pub lookahead: u32,
+	/// Max number of parachain blocks built on the same relay parent.
+	pub max_blocks_per_relay_parent: u32,
+}
+
+/// How the network routes horizontal messages from senders to recipients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HrmpRouting {
+	/// Hand messages to the recipient without going through the relay chain.
+	#[default]
+	Direct,
+	/// Commit messages into the storage of the relay chain `hrmp` pallet, from which the recipient
+	/// reads them in its next block, advancing its watermark and pruning the channels.
+	Relay,
+}
+
+/// A misconfiguration of a chain or network found by `validate` of a network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Misconfiguration {
+	/// The relay chain `chain` has no `configuration` pallet genesis.
+	MissingHostConfiguration { chain: &'static str },
+	/// The host configuration of the relay chain `chain` is inconsistent.
+	InconsistentHostConfiguration { chain: &'static str, error: String },
+	/// The universal location of the relay chain `chain` isn't a global consensus.
+	InvalidRelayUniversalLocation { chain: &'static str, actual: InteriorMultiLocation },
+	/// The parachain `chain` is declared with a para id other than the one of its `parachain_info`.
+	ParaIdMismatch { chain: &'static str, declared: u32, actual: u32 },
+	/// The universal location of the parachain `chain` isn't under its relay chain.
+	UniversalLocationMismatch {
+		chain: &'static str,
+		expected: InteriorMultiLocation,
+		actual: InteriorMultiLocation,
+	},
+	/// More than one parachain is declared with the para id.
+	DuplicateParaId(u32),
+	/// An HRMP channel is declared to a parachain itself or one not declared in the network.
+	InvalidHrmpChannel { sender: u32, recipient: u32 },
+}
+
+impl sp_std::fmt::Display for Misconfiguration {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
+		match self {
+			Self::MissingHostConfiguration { chain } => {
+				write!(f, "{}: no host configuration, the `configuration` pallet genesis is missing", chain)
+			}
+			Self::InconsistentHostConfiguration { chain, error } => {
+				write!(f, "{}: inconsistent host configuration: {}", chain, error)
+			}
+			Self::InvalidRelayUniversalLocation { chain, actual } => {
+				write!(f, "{}: universal location {:?} is not a global consensus", chain, actual)
+			}
+			Self::ParaIdMismatch { chain, declared, actual } => write!(
+				f,
+				"{}: declared as parachain {} but its `parachain_info` id is {}",
+				chain, declared, actual
+			),
+			Self::UniversalLocationMismatch { chain, expected, actual } => {
+				write!(f, "{}: universal location is {:?}, expected {:?}", chain, actual, expected)
+			}
+			Self::DuplicateParaId(para_id) => write!(f, "parachain {} is declared more than once", para_id),
+			Self::InvalidHrmpChannel { sender, recipient } => write!(
+				f,
+				"HRMP channel {} -> {} is not between two declared parachains",
+				sender, recipient
+			),
+		}
+	}
+}
+
+/// Misconfigurations of a network found by its `validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationReport {
+	/// Name of the network.
+	pub network: &'static str,
+	pub misconfigurations: Vec<Misconfiguration>,
+}
+
+impl sp_std::fmt::Display for ValidationReport {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
+		write!(f, "network {} is misconfigured:", self.network)?;
+		for misconfiguration in &self.misconfigurations {
+			write!(f, "\n  - {}", misconfiguration)?;
+		}
+		Ok(())
+	}
+}
+
+/// Queue where a message exceeding the weight available is kept until serviced manually.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverweightQueue {
+	/// `cumulus_pallet_dmp_queue` of the parachain.
+	Downward(u32),
+	/// `cumulus_pallet_xcmp_queue` of the parachain.
+	Horizontal(u32),
+	/// `ump` pallet of the relay chain.
+	Upward,
+}
+
+/// A message exceeding the weight available, waiting to be serviced with `service_overweight`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverweightMessage {
+	/// Queue the message is kept in.
+	pub queue: OverweightQueue,
+	/// Index of the message in the queue.
+	pub index: u64,
+	/// Location of the sender, relative to the recipient.
+	pub origin: MultiLocation,
+	/// Relay block number the message was sent at, if recorded by the queue.
+	pub sent_at: Option<RelayBlockNumber>,
+	/// The encoded message.
+	pub data: Vec<u8>,
+}
+
+impl OverweightMessage {
+	/// The XCM of the message, or `None` if it can't be decoded.
+	pub fn xcm(&self) -> Option<VersionedXcm<()>> {
+		VersionedXcm::decode_all_with_depth_limit(xcm::MAX_XCM_DECODE_DEPTH, &mut &self.data[..]).ok()
+	}
+}
+
+/// A parachain code upgrade rejected by the relay chain, which rejects the candidate carrying the code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectedCodeUpgrade {
+	pub para_id: u32,
+	/// Relay parent number of the parachain block carrying the code.
+	pub relay_parent_number: RelayBlockNumber,
+	pub error: frame_support::dispatch::DispatchError,
+}
+
+/// Transport of a message between chains of a network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+	/// From the relay chain to a parachain.
+	Downward,
+	/// From a parachain to the relay chain.
+	Upward,
+	/// From a parachain to a sibling parachain.
+	Horizontal,
+}
+
+/// A chain of a network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChainId {
+	/// The relay chain.
+	Relay,
+	/// The parachain of the para id.
+	Parachain(u32),
+}
+
+/// Execution status of a message sent in a network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageStatus {
+	/// Not executed yet: on its way, deferred or overweight.
+	Pending,
+	/// Executed completely.
+	Complete,
+	/// Executed with an error.
+	Failed,
+	/// Dropped without execution, as its sender or recipient was offboarded.
+	Dropped,
+}
+
+/// A message sent between chains of a network, as recorded in its journal. A horizontal message page
+/// is recorded as one entry per XCM in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+	pub kind: MessageKind,
+	pub sender: ChainId,
+	pub recipient: ChainId,
+	/// Relay block number the message was sent at.
+	pub sent_at: RelayBlockNumber,
+	/// Id of the message, the hash of `data`, as in the events of the message queues.
+	pub id: XcmHash,
+	/// The encoded XCM.
+	pub data: Vec<u8>,
+	/// Outcome of the execution of the message by the recipient, if executed.
+	pub outcome: Option<Outcome>,
+	/// If the message was dropped without execution, as its sender or recipient was offboarded.
+	pub dropped: bool,
+	/// Index in the journal of the message whose execution sent this message, if any.
+	pub cause: Option<usize>,
+}
+
+impl JournalEntry {
+	/// The XCM of the message, or `None` if it can't be decoded.
+	pub fn xcm(&self) -> Option<VersionedXcm<()>> {
+		VersionedXcm::decode_all_with_depth_limit(xcm::MAX_XCM_DECODE_DEPTH, &mut &self.data[..]).ok()
+	}
+
+	/// Weight used by the execution of the message, if executed.
+	pub fn weight_used(&self) -> Option<Weight> {
+		self.outcome.as_ref().map(Outcome::weight_used)
+	}
+
+	/// Error of the execution of the message, if executed with an error.
+	pub fn error(&self) -> Option<XcmError> {
+		match self.outcome {
+			Some(Outcome::Incomplete(_, error)) | Some(Outcome::Error(error)) => Some(error),
+			_ => None,
+		}
+	}
+
+	pub fn status(&self) -> MessageStatus {
+		match self.outcome {
+			None if self.dropped => MessageStatus::Dropped,
+			None => MessageStatus::Pending,
+			Some(Outcome::Complete(_)) => MessageStatus::Complete,
+			Some(_) => MessageStatus::Failed,
+		}
+	}
+}
+
+/// Filter of the messages in the journal of a network. The default filter matches every message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageFilter {
+	pub kind: Option<MessageKind>,
+	pub sender: Option<ChainId>,
+	pub recipient: Option<ChainId>,
+	pub status: Option<MessageStatus>,
+}
+
+impl MessageFilter {
+	/// Only messages of `kind`.
+	pub fn kind(mut self, kind: MessageKind) -> Self {
+		self.kind = Some(kind);
+		self
+	}
+
+	/// Only messages from `sender`.
+	pub fn sender(mut self, sender: ChainId) -> Self {
+		self.sender = Some(sender);
+		self
+	}
+
+	/// Only messages to `recipient`.
+	pub fn recipient(mut self, recipient: ChainId) -> Self {
+		self.recipient = Some(recipient);
+		self
+	}
+
+	/// Only messages of `status`.
+	pub fn status(mut self, status: MessageStatus) -> Self {
+		self.status = Some(status);
+		self
+	}
+
+	pub fn matches(&self, entry: &JournalEntry) -> bool {
+		self.kind.map_or(true, |kind| kind == entry.kind)
+			&& self.sender.map_or(true, |sender| sender == entry.sender)
+			&& self.recipient.map_or(true, |recipient| recipient == entry.recipient)
+			&& self.status.map_or(true, |status| status == entry.status())
+	}
+}
+
+/// An item of a horizontal message page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum XcmpPageItem {
+	/// An XCM of a `ConcatenatedVersionedXcm` page, with its encoding in the page.
+	Xcm { encoded: Vec<u8>, xcm: VersionedXcm<()> },
+	/// A blob of a `ConcatenatedEncodedBlob` page.
+	Blob(Vec<u8>),
+	/// A signal of a `Signals` page.
+	Signal(ChannelSignal),
+}
+
+/// A horizontal message page, split into its items.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XcmpPage {
+	pub format: XcmpMessageFormat,
+	/// Items of the page, in the order they are concatenated.
+	pub items: Vec<XcmpPageItem>,
+}
+
+impl XcmpPage {
+	/// Decode `page` as sent over an HRMP channel: its `XcmpMessageFormat`, then items of the format
+	/// concatenated up to the end of the page.
+	pub fn decode(page: &[u8]) -> Result<Self, codec::Error> {
+		let mut data = page;
+		let format = XcmpMessageFormat::decode(&mut data)?;
+
+		let mut items = Vec::new();
+		while !data.is_empty() {
+			items.push(Self::decode_item(format, &mut data)?);
+		}
+		Ok(Self { format, items })
+	}
+
+	/// Decode `page` up to its first item that can't be decoded, where `cumulus_pallet_xcmp_queue`
+	/// stops processing it. Returns the items decoded and the rest of the page from that item, empty if
+	/// the whole page decodes. Fails only if the format of the page can't be decoded.
+	pub fn decode_prefix(page: &[u8]) -> Result<(Self, &[u8]), codec::Error> {
+		let mut data = page;
+		let format = XcmpMessageFormat::decode(&mut data)?;
+
+		let mut items = Vec::new();
+		while !data.is_empty() {
+			let mut remaining = data;
+			match Self::decode_item(format, &mut remaining) {
+				Ok(item) => items.push(item),
+				Err(_) => break,
+			}
+			data = remaining;
+		}
+		Ok((Self { format, items }, data))
+	}
+
+	fn decode_item(format: XcmpMessageFormat, data: &mut &[u8]) -> Result<XcmpPageItem, codec::Error> {
+		let remaining = *data;
+		Ok(match format {
+			XcmpMessageFormat::ConcatenatedVersionedXcm => {
+				let xcm = VersionedXcm::<()>::decode_with_depth_limit(xcm::MAX_XCM_DECODE_DEPTH, data)?;
+				XcmpPageItem::Xcm { encoded: remaining[..remaining.len() - data.len()].to_vec(), xcm }
+			}
+			XcmpMessageFormat::ConcatenatedEncodedBlob => XcmpPageItem::Blob(Vec::<u8>::decode(data)?),
+			XcmpMessageFormat::Signals => XcmpPageItem::Signal(ChannelSignal::decode(data)?),
+		})
+	}
+
+	/// XCMs of the page.
+	pub fn xcms(&self) -> impl Iterator<Item = &VersionedXcm<()>> {
+		self.items.iter().filter_map(|item| match item {
+			XcmpPageItem::Xcm { xcm, .. } => Some(xcm),
+			_ => None,
+		})
+	}
+}
+
+/// A message executed by a chain, as reported by its message queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecutedMessage {
+	pub kind: MessageKind,
+	pub id: XcmHash,
+	pub outcome: Outcome,
+	/// Ids of the messages the chain sent during the execution.
+	pub sent: Vec<XcmHash>,
+}
+
+/// The execution of a message, or a message sent, as reported by an event of a message queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReportedMessage {
+	Executed { kind: MessageKind, id: XcmHash, outcome: Outcome },
+	Sent(XcmHash),
+}
+
+/// Pallet or message handler of a parachain reporting the messages it executes or sends in the events
+/// of the runtime `T`. Implemented by `cumulus_pallet_dmp_queue`, `cumulus_pallet_xcmp_queue` and
+/// `cumulus_pallet_parachain_system`; other message handlers of a parachain must implement it for
+/// their messages to be recorded in the journal, possibly reporting nothing with the default.
+pub trait ReportMessages<T: frame_system::Config> {
+	/// The message reported by `event`, if any.
+	fn reported_message(_event: &T::RuntimeEvent) -> Option<ReportedMessage> {
+		None
+	}
+}
+
+impl<T: frame_system::Config> ReportMessages<T> for () {}
+
+impl<T: frame_system::Config, A: ReportMessages<T>, B: ReportMessages<T>, C: ReportMessages<T>> ReportMessages<T>
+	for (A, B, C)
+{
+	fn reported_message(event: &T::RuntimeEvent) -> Option<ReportedMessage> {
+		A::reported_message(event)
+			.or_else(|| B::reported_message(event))
+			.or_else(|| C::reported_message(event))
+	}
+}
+
+impl<T> ReportMessages<T> for cumulus_pallet_parachain_system::Pallet<T>
+where
+	T: cumulus_pallet_parachain_system::Config,
+	<T as frame_system::Config>::RuntimeEvent: TryInto<cumulus_pallet_parachain_system::Event<T>>,
+{
+	fn reported_message(event: &T::RuntimeEvent) -> Option<ReportedMessage> {
+		match TryInto::<cumulus_pallet_parachain_system::Event<T>>::try_into(event.clone()).ok()? {
+			cumulus_pallet_parachain_system::Event::UpwardMessageSent { message_hash: Some(id), .. } => {
+				Some(ReportedMessage::Sent(id))
+			}
+			_ => None,
+		}
+	}
+}
+
+/// Downward messages kept in the overweight queue are reported as failed with `WeightLimitReached`.
+impl<T> ReportMessages<T> for cumulus_pallet_dmp_queue::Pallet<T>
+where
+	T: cumulus_pallet_dmp_queue::Config,
+	<T as frame_system::Config>::RuntimeEvent: TryInto<cumulus_pallet_dmp_queue::Event<T>>,
+{
+	fn reported_message(event: &T::RuntimeEvent) -> Option<ReportedMessage> {
+		use cumulus_pallet_dmp_queue::Event as DmpEvent;
+
+		let (id, outcome) = match TryInto::<DmpEvent<T>>::try_into(event.clone()).ok()? {
+			DmpEvent::ExecutedDownward { message_id, outcome, .. } => (message_id, outcome),
+			DmpEvent::InvalidFormat { message_id, .. } => (message_id, Outcome::Error(XcmError::FailedToDecode)),
+			DmpEvent::UnsupportedVersion { message_id, .. } => {
+				(message_id, Outcome::Error(XcmError::UnhandledXcmVersion))
+			}
+			DmpEvent::OverweightEnqueued { message_id, required_weight, .. } => {
+				(message_id, Outcome::Error(XcmError::WeightLimitReached(required_weight)))
+			}
+			_ => return None,
+		};
+		Some(ReportedMessage::Executed { kind: MessageKind::Downward, id, outcome })
+	}
+}
+
+impl<T> ReportMessages<T> for cumulus_pallet_xcmp_queue::Pallet<T>
+where
+	T: cumulus_pallet_xcmp_queue::Config,
+	<T as frame_system::Config>::RuntimeEvent: TryInto<cumulus_pallet_xcmp_queue::Event<T>>,
+{
+	fn reported_message(event: &T::RuntimeEvent) -> Option<ReportedMessage> {
+		use cumulus_pallet_xcmp_queue::Event as XcmpEvent;
+
+		let (id, outcome) = match TryInto::<XcmpEvent<T>>::try_into(event.clone()).ok()? {
+			XcmpEvent::XcmpMessageSent { message_hash: Some(id), .. } => return Some(ReportedMessage::Sent(id)),
+			XcmpEvent::Success { message_hash: Some(id), weight, .. } => (id, Outcome::Complete(weight)),
+			XcmpEvent::Fail { message_hash: Some(id), error, weight, .. } if weight.is_zero() => {
+				(id, Outcome::Error(error))
+			}
+			XcmpEvent::Fail { message_hash: Some(id), error, weight, .. } => (id, Outcome::Incomplete(weight, error)),
+			XcmpEvent::BadVersion { message_hash: Some(id), .. } => (id, Outcome::Error(XcmError::UnhandledXcmVersion)),
+			_ => return None,
+		};
+		Some(ReportedMessage::Executed { kind: MessageKind::Horizontal, id, outcome })
+	}
+}
+
+/// Messages executed by a parachain since the event `since` of the block, as reported by `R`, its
+/// `ReportMessages` pallets and message handlers. Messages sent during the execution of a message are
+/// the ones reported as sent since the previous execution.
+///
+/// Must be called in the parachain externalities.
+pub fn parachain_executed_messages<T: frame_system::Config, R: ReportMessages<T>>(since: u32) -> Vec<ExecutedMessage> {
+	let mut executed = Vec::new();
+	let mut sent = Vec::new();
+	for record in frame_system::Pallet::<T>::events().into_iter().skip(since as usize) {
+		match R::reported_message(&record.event) {
+			Some(ReportedMessage::Sent(id)) => sent.push(id),
+			Some(ReportedMessage::Executed { kind, id, outcome }) => {
+				executed.push(ExecutedMessage { kind, id, outcome, sent: sp_std::mem::take(&mut sent) })
+			}
+			None => {}
+		}
+	}
+	executed
+}
+
+/// Upward messages executed by a relay chain since the event `since` of the block, as reported by
+/// the `ump` pallet. Messages sent during the execution aren't reported.
+///
+/// Must be called in the relay chain externalities.
+pub fn relay_executed_messages<T>(since: u32) -> Vec<ExecutedMessage>
+where
+	T: ump::Config,
+	<T as frame_system::Config>::RuntimeEvent: TryInto<ump::Event<T>>,
+{
+	frame_system::Pallet::<T>::events()
+		.into_iter()
+		.skip(since as usize)
+		.filter_map(|record| {
+			let (id, outcome) = match TryInto::<ump::Event<T>>::try_into(record.event).ok()? {
+				ump::Event::ExecutedUpward(id, outcome) => (id, outcome),
+				ump::Event::InvalidFormat(id) => (id, Outcome::Error(XcmError::FailedToDecode)),
+				ump::Event::UnsupportedVersion(id) => (id, Outcome::Error(XcmError::UnhandledXcmVersion)),
+				_ => return None,
+			};
+			Some(ExecutedMessage { kind: MessageKind::Upward, id, outcome, sent: Vec::new() })
+		})
+		.collect()
+}
+
+/// A message in the trace of a network, with the messages sent by its execution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedMessage {
+	pub entry: JournalEntry,
+	/// Name of the sender chain, as declared in the network.
+	pub sender_name: &'static str,
+	/// Name of the recipient chain, as declared in the network.
+	pub recipient_name: &'static str,
+	/// Messages sent by the execution of the message, in the order they were sent.
+	pub children: Vec<TracedMessage>,
+}
+
+impl TracedMessage {
+	fn fmt_with_depth(&self, f: &mut sp_std::fmt::Formatter, depth: usize) -> sp_std::fmt::Result {
+		let indent = depth * 2;
+		let outcome = match &self.entry.outcome {
+			Some(outcome) => format!("{:?}", outcome),
+			None if self.entry.dropped => "dropped".into(),
+			None => "pending".into(),
+		};
+		writeln!(
+			f,
+			"{:indent$}{} -> {} ({:?}, relay block {}): {}",
+			"",
+			self.sender_name,
+			self.recipient_name,
+			self.entry.kind,
+			self.entry.sent_at,
+			outcome,
+			indent = indent,
+		)?;
+		match self.entry.xcm().and_then(|xcm| Xcm::<()>::try_from(xcm).ok()) {
+			Some(xcm) => {
+				for instruction in xcm.0 {
+					writeln!(f, "{:indent$}  | {:?}", "", instruction, indent = indent)?;
+				}
+			}
+			None => writeln!(f, "{:indent$}  | <undecodable>", "", indent = indent)?,
+		}
+		for child in &self.children {
+			child.fmt_with_depth(f, depth + 1)?;
+		}
+		Ok(())
+	}
+}
+
+/// Messages of the journal of a network arranged as trees, each message under the message whose
+/// execution sent it. Displayed as an indented tree with the instructions and outcome of each message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageTrace {
+	/// Messages not sent by the execution of another message, in the order they were sent.
+	pub roots: Vec<TracedMessage>,
+}
+
+impl MessageTrace {
+	/// Trace of the messages of `journal`, naming chains with `chain_name`.
+	pub fn new(journal: &[JournalEntry], chain_name: impl Fn(ChainId) -> &'static str) -> Self {
+		fn traced(
+			index: usize,
+			journal: &[JournalEntry],
+			chain_name: &impl Fn(ChainId) -> &'static str,
+		) -> TracedMessage {
+			let entry = journal[index].clone();
+			TracedMessage {
+				sender_name: chain_name(entry.sender),
+				recipient_name: chain_name(entry.recipient),
+				children: (index + 1..journal.len())
+					.filter(|i| journal[*i].cause == Some(index))
+					.map(|i| traced(i, journal, chain_name))
+					.collect(),
+				entry,
+			}
+		}
+
+		Self {
+			roots: (0..journal.len())
+				.filter(|i| journal[*i].cause.is_none())
+				.map(|i| traced(i, journal, &chain_name))
+				.collect(),
+		}
+	}
+}
+
+impl sp_std::fmt::Display for MessageTrace {
+	fn fmt(&self, f: &mut sp_std::fmt::Formatter) -> sp_std::fmt::Result {
+		for message in &self.roots {
+			message.fmt_with_depth(f, 0)?;
+		}
+		Ok(())
+	}
+}
+
+/// A message exported by a bridge hub to a bridged network with `ExportMessage`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BridgeMessage {
+	/// Network the message is exported to.
+	pub network: NetworkId,
+	/// Universal location of the sender.
+	pub universal_source: InteriorMultiLocation,
+	/// Destination of the message within `network`.
+	pub destination: InteriorMultiLocation,
+	/// The exported message.
+	pub message: Xcm<()>,
+}
+
+/// A message relayed by a bridge to the bridge hub of its destination network, with the result of
+/// the bridge hub sending it on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayedBridgeMessage {
+	pub message: BridgeMessage,
+	pub result: Result<XcmHash, SendError>,
+}
+
+/// `MessageExporter` of bridge hubs, which keeps exported messages until a bridge declared with
+/// `decl_test_bridge!` relays them to the bridged network.
+pub struct BridgeMessageExporter;
+
+impl ExportXcm for BridgeMessageExporter {
+	type Ticket = BridgeMessage;
+
+	fn validate(
+		network: NetworkId,
+		_channel: u32,
+		universal_source: &mut Option<InteriorMultiLocation>,
+		destination: &mut Option<InteriorMultiLocation>,
+		message: &mut Option<Xcm<()>>,
+	) -> SendResult<BridgeMessage> {
+		let message = BridgeMessage {
+			network,
+			universal_source: universal_source.take().ok_or(SendError::MissingArgument)?,
+			destination: destination.take().ok_or(SendError::MissingArgument)?,
+			message: message.take().ok_or(SendError::MissingArgument)?,
+		};
+		Ok((message, MultiAssets::new()))
+	}
+
+	fn deliver(message: BridgeMessage) -> Result<XcmHash, SendError> {
+		let hash = (&message.network, &message.universal_source, &message.destination, &message.message)
+			.using_encoded(sp_io::hashing::blake2_256);
+		BRIDGED_MESSAGES.with(|b| b.borrow_mut().entry(message.network).or_default().push_back(message));
+		Ok(hash)
+	}
+}
+
+/// A bridge between networks of different consensus, declared with `decl_test_bridge!`.
+pub trait Bridge {
+	/// Relay messages exported to the bridged networks, returning if any message was relayed.
+	fn relay_messages() -> bool;
+}
+
+impl Bridge for () {
+	fn relay_messages() -> bool {
+		false
+	}
+}
+
+/// Clear the messages exported to or by `network`, and those relayed to it.
+pub fn clear_bridged_messages(network: NetworkId) {
+	BRIDGED_MESSAGES.with(|b| {
+		let mut b = b.borrow_mut();
+		b.remove(&network);
+		for messages in b.values_mut() {
+			messages.retain(|m| m.universal_source.first() != Some(&GlobalConsensus(network)));
+		}
+	});
+	RELAYED_BRIDGE_MESSAGES.with(|b| b.borrow_mut().remove(&network));
+}
+
+/// The messages exported to or by `network` and not relayed yet, and those relayed to it.
+pub fn bridged_messages_of(network: NetworkId) -> (Vec<BridgeMessage>, Vec<RelayedBridgeMessage>) {
+	let bridged = BRIDGED_MESSAGES.with(|b| {
+		b.borrow()
+			.values()
+			.flatten()
+			.filter(|m| m.network == network || m.universal_source.first() == Some(&GlobalConsensus(network)))
+			.cloned()
+			.collect()
+	});
+	let relayed = RELAYED_BRIDGE_MESSAGES.with(|b| b.borrow().get(&network).cloned().unwrap_or_default());
+	(bridged, relayed)
+}
+
+/// Replace the messages exported to or by `network`, and those relayed to it, with messages taken by
+/// `bridged_messages_of`. Messages of `network` are queued after those of other networks.
+pub fn restore_bridged_messages(network: NetworkId, messages: (Vec<BridgeMessage>, Vec<RelayedBridgeMessage>)) {
+	let (bridged, relayed) = messages;
+	clear_bridged_messages(network);
+	BRIDGED_MESSAGES.with(|b| {
+		let mut b = b.borrow_mut();
+		for message in bridged {
+			b.entry(message.network).or_default().push_back(message);
+		}
+	});
+	if !relayed.is_empty() {
+		RELAYED_BRIDGE_MESSAGES.with(|b| b.borrow_mut().insert(network, relayed));
+	}
+}
+
+/// Send `message` from the bridge hub `bridge_hub` to its destination within the local network, with
+/// the origin set to the universal location of the sender, as a bridge blob dispatcher does.
+///
+/// Must be called in the bridge hub externalities.
+pub fn dispatch_bridged_message<Router: SendXcm>(
+	message: BridgeMessage,
+	bridge_hub: ParaId,
+) -> Result<XcmHash, SendError> {
+	let BridgeMessage {
+		mut universal_source,
+		mut destination,
+		message,
+		..
+	} = message;
+
+	let source_network = match universal_source.take_first() {
+		Some(GlobalConsensus(network)) => network,
+		_ => return Err(SendError::Unroutable),
+	};
+	let mut instructions = vec![UniversalOrigin(GlobalConsensus(source_network))];
+	if universal_source != Here {
+		instructions.push(DescendOrigin(universal_source));
+	}
+	instructions.extend(message.0);
+
+	let dest = if destination.first() == Some(&Junction::Parachain(bridge_hub.into())) {
+		destination.take_first();
+		MultiLocation::new(0, destination)
+	} else {
+		MultiLocation::new(1, destination)
+	};
+	xcm::v3::send_xcm::<Router>(dest, Xcm(instructions)).map(|(hash, _)| hash)
+}
+
+/// The part of relay chain host `config` parachains read from the relay chain state proof.
+pub fn abridged_host_configuration(config: &HostConfiguration<RelayBlockNumber>) -> AbridgedHostConfiguration {
+	AbridgedHostConfiguration {
+		max_code_size: config.max_code_size,
+		max_head_data_size: config.max_head_data_size,
+		max_upward_queue_count: config.max_upward_queue_count,
+		max_upward_queue_size: config.max_upward_queue_size,
+		max_upward_message_size: config.max_upward_message_size,
+		max_upward_message_num_per_candidate: config.max_upward_message_num_per_candidate,
+		hrmp_max_message_num_per_candidate: config.hrmp_max_message_num_per_candidate,
+		validation_upgrade_cooldown: config.validation_upgrade_cooldown,
+		validation_upgrade_delay: config.validation_upgrade_delay,
+	}
+}
+
+/// Remove the first `processed` downward messages to `para_id` from the queue of the relay chain dmp
+/// pallet named `pallet_name`, as `dmp::Pallet::prune_dmq` does on the inclusion of a candidate that
+/// processed them.
+///
+/// Must be called in relay chain externalities.
+pub fn prune_dmq(pallet_name: &str, para_id: ParaId, processed: u32) {
+	let key = Twox64Concat::hash(&para_id.encode());
+	let mut queue: Vec<InboundDownwardMessage> =
+		get_storage_value(pallet_name.as_bytes(), b"DownwardMessageQueues", &key).unwrap_or_default();
+
+	let processed = (processed as usize).min(queue.len());
+	queue.drain(..processed);
+
+	if queue.is_empty() {
+		take_storage_value::<Vec<InboundDownwardMessage>>(pallet_name.as_bytes(), b"DownwardMessageQueues", &key);
+	} else {
+		put_storage_value(pallet_name.as_bytes(), b"DownwardMessageQueues", &key, queue);
+	}
+}
+
+/// Remove the downward message queue of `para_id` and its MQC head from the relay chain dmp pallet
+/// named `pallet_name`, as for an outgoing parachain.
+///
+/// Must be called in relay chain externalities.
+pub fn clean_dmq(pallet_name: &str, para_id: ParaId) {
+	let key = Twox64Concat::hash(&para_id.encode());
+	take_storage_value::<Vec<InboundDownwardMessage>>(pallet_name.as_bytes(), b"DownwardMessageQueues", &key);
+	take_storage_value::<RelayHash>(pallet_name.as_bytes(), b"DownwardMessageQueueHeads", &key);
+}
+
+/// MQC head of the downward messages to `para_id` of the relay chain dmp pallet named `pallet_name`.
+///
+/// Must be called in relay chain externalities.
+pub fn dmq_mqc_head(pallet_name: &str, para_id: ParaId) -> RelayHash {
+	let key = Twox64Concat::hash(&para_id.encode());
+	get_storage_value(pallet_name.as_bytes(), b"DownwardMessageQueueHeads", &key).unwrap_or_default()
+}
+
+/// Downward messages waiting in the `cumulus_pallet_dmp_queue` instance named `pallet_name`.
+///
+/// Must be called in parachain externalities.
+pub fn deferred_dmp_queue_messages(pallet_name: &str) -> Vec<(RelayBlockNumber, Vec<u8>)> {
+	// `PageIndexData { begin_used, end_used, overweight_count }`
+	let (begin_used, end_used, _): (u32, u32, u64) =
+		get_storage_value(pallet_name.as_bytes(), b"PageIndex", &[]).unwrap_or_default();
+
+	(begin_used..end_used)
+		.flat_map(|page| {
+			get_storage_value::<Vec<(RelayBlockNumber, Vec<u8>)>>(
+				pallet_name.as_bytes(),
+				b"Pages",
+				&Blake2_128Concat::hash(&page.encode()),
+			)
+			.unwrap_or_default()
+		})
+		.collect()
+}
+
+/// Horizontal messages waiting in the `cumulus_pallet_xcmp_queue` instance named `pallet_name`.
+///
+/// Must be called in parachain externalities.
+pub fn deferred_xcmp_queue_messages(pallet_name: &str) -> Vec<(ParaId, RelayBlockNumber, Vec<u8>)> {
+	// `InboundChannelDetails { sender, state, message_metadata }`
+	#[allow(clippy::type_complexity)]
+	let status: Vec<(ParaId, u8, Vec<(RelayBlockNumber, XcmpMessageFormat)>)> =
+		get_storage_value(pallet_name.as_bytes(), b"InboundXcmpStatus", &[]).unwrap_or_default();
+
+	status
+		.into_iter()
+		.flat_map(|(sender, _, message_metadata)| {
+			message_metadata.into_iter().map(move |(sent_at, format)| {
+				let key = [
+					Blake2_128Concat::hash(&sender.encode()),
+					Twox64Concat::hash(&sent_at.encode()),
+				]
+				.concat();
+				let data: Vec<u8> =
+					get_storage_value(pallet_name.as_bytes(), b"InboundXcmpMessages", &key).unwrap_or_default();
+
+				// pages are stored without the format prefix
+				let mut msg = format.encode();
+				msg.extend(data);
+				(sender, sent_at, msg)
+			})
+		})
+		.collect()
+}
+
+/// Overweight messages in the `cumulus_pallet_dmp_queue` instance named `pallet_name` of `para_id`.
+///
+/// Must be called in parachain externalities.
+pub fn overweight_dmp_queue_messages(pallet_name: &str, para_id: u32) -> Vec<OverweightMessage> {
+	let mut messages = storage_key_iter::<u64, (RelayBlockNumber, Vec<u8>), Blake2_128Concat>(
+		pallet_name.as_bytes(),
+		b"Overweight",
+	)
+	.map(|(index, (sent_at, data))| OverweightMessage {
+		queue: OverweightQueue::Downward(para_id),
+		index,
+		origin: MultiLocation::parent(),
+		sent_at: Some(sent_at),
+		data,
+	})
+	.collect::<Vec<_>>();
+	messages.sort_by_key(|m| m.index);
+	messages
+}
+
+/// Overweight messages in the `cumulus_pallet_xcmp_queue` instance named `pallet_name` of `para_id`.
+///
+/// Must be called in parachain externalities.
+pub fn overweight_xcmp_queue_messages(pallet_name: &str, para_id: u32) -> Vec<OverweightMessage> {
+	let mut messages = storage_key_iter::<u64, (ParaId, RelayBlockNumber, Vec<u8>), Twox64Concat>(
+		pallet_name.as_bytes(),
+		b"Overweight",
+	)
+	.map(|(index, (sender, sent_at, data))| OverweightMessage {
+		queue: OverweightQueue::Horizontal(para_id),
+		index,
+		origin: MultiLocation::new(1, X1(Junction::Parachain(sender.into()))),
+		sent_at: Some(sent_at),
+		data,
+	})
+	.collect::<Vec<_>>();
+	messages.sort_by_key(|m| m.index);
+	messages
+}
+
+/// Overweight messages in the relay chain `ump` pallet named `pallet_name`.
+///
+/// Must be called in relay chain externalities.
+pub fn overweight_ump_messages(pallet_name: &str) -> Vec<OverweightMessage> {
+	let mut messages =
+		storage_key_iter::<u64, (ParaId, Vec<u8>), Twox64Concat>(pallet_name.as_bytes(), b"Overweight")
+			.map(|(index, (sender, data))| OverweightMessage {
+				queue: OverweightQueue::Upward,
+				index,
+				origin: Junction::Parachain(sender.into()).into(),
+				sent_at: None,
+				data,
+			})
+			.collect::<Vec<_>>();
+	messages.sort_by_key(|m| m.index);
+	messages
+}
+
+/// Keep an upward message from `sender` exceeding `ump_max_individual_weight` in the overweight queue
+/// of the relay chain `ump` pallet named `pallet_name`, as the pallet does. Returns the index of the
+/// message.
+///
+/// Must be called in relay chain externalities.
+pub fn stash_overweight_ump_message(pallet_name: &str, sender: ParaId, msg: Vec<u8>) -> u64 {
+	let index: u64 = get_storage_value(pallet_name.as_bytes(), b"OverweightCount", &[]).unwrap_or_default();
+	put_storage_value(pallet_name.as_bytes(), b"OverweightCount", &[], index + 1);
+	put_storage_value(
+		pallet_name.as_bytes(),
+		b"Overweight",
+		&Twox64Concat::hash(&index.encode()),
+		(sender, msg),
+	);
+	index
+}
+
+/// Onboard `para_id` as a parachain in the relay chain `paras` pallet named `pallet_name`, or offboard
+/// it if not `registered`.
+///
+/// Must be called in relay chain externalities.
+pub fn set_parachain_registered(pallet_name: &str, para_id: ParaId, registered: bool) {
+	let mut parachains: Vec<ParaId> =
+		get_storage_value(pallet_name.as_bytes(), b"Parachains", &[]).unwrap_or_default();
+	match (parachains.binary_search(&para_id), registered) {
+		(Err(idx), true) => parachains.insert(idx, para_id),
+		(Ok(idx), false) => {
+			parachains.remove(idx);
+		}
+		_ => {}
+	}
+	put_storage_value(pallet_name.as_bytes(), b"Parachains", &[], parachains);
+
+	let key = Twox64Concat::hash(&para_id.encode());
+	if registered {
+		put_storage_value(
+			pallet_name.as_bytes(),
+			b"ParaLifecycles",
+			&key,
+			paras::ParaLifecycle::Parachain,
+		);
+	} else {
+		take_storage_value::<paras::ParaLifecycle>(pallet_name.as_bytes(), b"ParaLifecycles", &key);
+	}
+}
+
+/// Open the HRMP channel from `sender` to `recipient` with the limits of `config` in the relay chain
+/// `hrmp` pallet named `pallet_name`, if not open yet.
+///
+/// Must be called in relay chain externalities.
+pub fn open_hrmp_channel(pallet_name: &str, sender: ParaId, recipient: ParaId, config: HrmpChannelConfig) {
+	let channel_key = Twox64Concat::hash(&HrmpChannelId { sender, recipient }.encode());
+	if get_storage_value::<HrmpChannel>(pallet_name.as_bytes(), b"HrmpChannels", &channel_key).is_some() {
+		return;
+	}
+
+	put_storage_value(
+		pallet_name.as_bytes(),
+		b"HrmpChannels",
+		&channel_key,
+		HrmpChannel {
+			max_capacity: config.max_capacity,
+			max_total_size: config.max_total_size,
+			max_message_size: config.max_message_size,
+			msg_count: 0,
+			total_size: 0,
+			mqc_head: None,
+			sender_deposit: 0,
+			recipient_deposit: 0,
+		},
+	);
+	for (index, para_id, counterparty) in [
+		(&b"HrmpEgressChannelsIndex"[..], sender, recipient),
+		(&b"HrmpIngressChannelsIndex"[..], recipient, sender),
+	] {
+		let key = Twox64Concat::hash(&para_id.encode());
+		let mut channels: Vec<ParaId> = get_storage_value(pallet_name.as_bytes(), index, &key).unwrap_or_default();
+		if let Err(idx) = channels.binary_search(&counterparty) {
+			channels.insert(idx, counterparty);
+		}
+		put_storage_value(pallet_name.as_bytes(), index, &key, channels);
+	}
+}
+
+/// Close every HRMP channel of `para_id` in the relay chain `hrmp` pallet named `pallet_name`,
+/// dropping the messages in them.
+///
+/// Must be called in relay chain externalities.
+pub fn close_hrmp_channels(pallet_name: &str, para_id: ParaId) {
+	let para_key = Twox64Concat::hash(&para_id.encode());
+	for (index, counterparty_index, outgoing) in [
+		(&b"HrmpEgressChannelsIndex"[..], &b"HrmpIngressChannelsIndex"[..], true),
+		(&b"HrmpIngressChannelsIndex"[..], &b"HrmpEgressChannelsIndex"[..], false),
+	] {
+		let counterparties: Vec<ParaId> =
+			take_storage_value(pallet_name.as_bytes(), index, &para_key).unwrap_or_default();
+		for counterparty in counterparties {
+			let (sender, recipient) = if outgoing { (para_id, counterparty) } else { (counterparty, para_id) };
+			let channel_key = Twox64Concat::hash(&HrmpChannelId { sender, recipient }.encode());
+			take_storage_value::<HrmpChannel>(pallet_name.as_bytes(), b"HrmpChannels", &channel_key);
+			take_storage_value::<Vec<InboundHrmpMessage>>(pallet_name.as_bytes(), b"HrmpChannelContents", &channel_key);
+
+			let key = Twox64Concat::hash(&counterparty.encode());
+			let mut channels: Vec<ParaId> =
+				get_storage_value(pallet_name.as_bytes(), counterparty_index, &key).unwrap_or_default();
+			channels.retain(|p| *p != para_id);
+			put_storage_value(pallet_name.as_bytes(), counterparty_index, &key, channels);
+		}
+	}
+	take_storage_value::<Vec<(RelayBlockNumber, Vec<ParaId>)>>(pallet_name.as_bytes(), b"HrmpChannelDigests", &para_key);
+	take_storage_value::<RelayBlockNumber>(pallet_name.as_bytes(), b"HrmpWatermarks", &para_key);
+}
+
+/// HRMP channels of `para_id` in the relay chain `hrmp` pallet named `pallet_name`: the recipients of
+/// its egress channels, the senders of its ingress channels, and the channels in both indexes.
+///
+/// Must be called in relay chain externalities.
+#[allow(clippy::type_complexity)]
+pub fn hrmp_channels_of(
+	pallet_name: &str,
+	para_id: ParaId,
+) -> (Vec<ParaId>, Vec<ParaId>, BTreeMap<HrmpChannelId, HrmpChannel>) {
+	let para_key = Twox64Concat::hash(&para_id.encode());
+	let egress: Vec<ParaId> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpEgressChannelsIndex", &para_key).unwrap_or_default();
+	let ingress: Vec<ParaId> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpIngressChannelsIndex", &para_key).unwrap_or_default();
+
+	let channels = egress
+		.iter()
+		.map(|recipient| HrmpChannelId { sender: para_id, recipient: *recipient })
+		.chain(ingress.iter().map(|sender| HrmpChannelId { sender: *sender, recipient: para_id }))
+		.filter_map(|id| {
+			let channel_key = Twox64Concat::hash(&id.encode());
+			get_storage_value(pallet_name.as_bytes(), b"HrmpChannels", &channel_key).map(|channel| (id, channel))
+		})
+		.collect();
+	(egress, ingress, channels)
+}
+
+/// Commit horizontal messages from `sender` to `recipient` included in the relay chain block
+/// `relay_block` into the relay chain `hrmp` pallet named `pallet_name`, as the pallet does with the
+/// outbound messages of a candidate: the channel contents, occupancy and MQC head, and the channel
+/// digest of the recipient are updated.
+///
+/// Must be called in relay chain externalities.
+pub fn queue_outbound_hrmp(
+	pallet_name: &str,
+	sender: ParaId,
+	recipient: ParaId,
+	relay_block: RelayBlockNumber,
+	messages: Vec<Vec<u8>>,
+) {
+	let channel_key = Twox64Concat::hash(&HrmpChannelId { sender, recipient }.encode());
+	let mut channel: HrmpChannel = get_storage_value(pallet_name.as_bytes(), b"HrmpChannels", &channel_key)
+		.unwrap_or_else(|| panic!("no HRMP channel from {:?} to {:?} in the relay chain", sender, recipient));
+	let mut contents: Vec<InboundHrmpMessage> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpChannelContents", &channel_key).unwrap_or_default();
+	let mut mqc = channel
+		.mqc_head
+		.map(|head| MessageQueueChain::decode(&mut &head.encode()[..]).expect("MQC is a head hash; qed"))
+		.unwrap_or_default();
+
+	for data in messages {
+		let msg = InboundHrmpMessage { sent_at: relay_block, data };
+		mqc.extend_hrmp(&msg);
+		channel.msg_count += 1;
+		channel.total_size += msg.data.len() as u32;
+		contents.push(msg);
+	}
+	channel.mqc_head = Some(mqc.head());
+
+	put_storage_value(pallet_name.as_bytes(), b"HrmpChannels", &channel_key, channel);
+	put_storage_value(pallet_name.as_bytes(), b"HrmpChannelContents", &channel_key, contents);
+
+	let recipient_key = Twox64Concat::hash(&recipient.encode());
+	let mut digests: Vec<(RelayBlockNumber, Vec<ParaId>)> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpChannelDigests", &recipient_key).unwrap_or_default();
+	match digests.last_mut() {
+		Some((block, senders)) if *block == relay_block => {
+			if let Err(idx) = senders.binary_search(&sender) {
+				senders.insert(idx, sender);
+			}
+		}
+		_ => digests.push((relay_block, vec![sender])),
+	}
+	put_storage_value(pallet_name.as_bytes(), b"HrmpChannelDigests", &recipient_key, digests);
+}
+
+/// Take the messages of the ingress channels of `recipient` sent up to `watermark` from the relay
+/// chain `hrmp` pallet named `pallet_name`, as the pallet prunes them when a candidate of the recipient
+/// advances its watermark.
+///
+/// Must be called in relay chain externalities.
+pub fn prune_hrmp(
+	pallet_name: &str,
+	recipient: ParaId,
+	watermark: RelayBlockNumber,
+) -> BTreeMap<ParaId, Vec<InboundHrmpMessage>> {
+	let recipient_key = Twox64Concat::hash(&recipient.encode());
+	let senders: Vec<ParaId> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpIngressChannelsIndex", &recipient_key).unwrap_or_default();
+
+	let mut messages = BTreeMap::new();
+	for sender in senders {
+		let channel_key = Twox64Concat::hash(&HrmpChannelId { sender, recipient }.encode());
+		let mut contents: Vec<InboundHrmpMessage> =
+			get_storage_value(pallet_name.as_bytes(), b"HrmpChannelContents", &channel_key).unwrap_or_default();
+		let received = contents.iter().take_while(|msg| msg.sent_at <= watermark).count();
+		if received == 0 {
+			continue;
+		}
+		let received = contents.drain(..received).collect::<Vec<_>>();
+		put_storage_value(pallet_name.as_bytes(), b"HrmpChannelContents", &channel_key, contents);
+
+		if let Some(mut channel) =
+			get_storage_value::<HrmpChannel>(pallet_name.as_bytes(), b"HrmpChannels", &channel_key)
+		{
+			channel.msg_count = channel.msg_count.saturating_sub(received.len() as u32);
+			channel.total_size = channel
+				.total_size
+				.saturating_sub(received.iter().map(|msg| msg.data.len() as u32).sum());
+			put_storage_value(pallet_name.as_bytes(), b"HrmpChannels", &channel_key, channel);
+		}
+		messages.insert(sender, received);
+	}
+
+	let mut digests: Vec<(RelayBlockNumber, Vec<ParaId>)> =
+		get_storage_value(pallet_name.as_bytes(), b"HrmpChannelDigests", &recipient_key).unwrap_or_default();
+	digests.retain(|(block, _)| *block > watermark);
+	put_storage_value(pallet_name.as_bytes(), b"HrmpChannelDigests", &recipient_key, digests);
+	put_storage_value(pallet_name.as_bytes(), b"HrmpWatermarks", &recipient_key, watermark);
+
+	messages
+}
+
+/// Replace the runtime code `:code` with `code` and deposit `CodeUpdated`, as `set_code` does
+/// without checking the runtime version. Must be called in the chain externalities.
+pub fn update_code_in_storage<T: frame_system::Config>(code: &[u8]) {
+	frame_support::storage::unhashed::put_raw(b":code", code);
+	frame_system::Pallet::<T>::deposit_event(frame_system::Event::<T>::CodeUpdated);
+}
+
+/// Key-value pairs of the main storage trie of a chain.
+pub type StorageSnapshot = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Read every key-value pair of the main storage trie. Must be called in the chain externalities.
+pub fn storage_snapshot() -> StorageSnapshot {
+	let mut snapshot = Vec::new();
+	let mut key = Vec::new();
+	while let Some(next) = sp_io::storage::next_key(&key) {
+		let value = sp_io::storage::get(&next).expect("next_key returns existing keys; qed");
+		snapshot.push((next.clone(), value.to_vec()));
+		key = next;
+	}
+	snapshot
+}
+
+/// Replace the main storage trie with `snapshot`. Must be called in the chain externalities.
+pub fn restore_storage(snapshot: &[(Vec<u8>, Vec<u8>)]) {
+	let _ = sp_io::storage::clear_prefix(&[], None);
+	for (key, value) in snapshot {
+		sp_io::storage::set(key, value);
+	}
+}
+
 #[macro_export]
 macro_rules! decl_test_relay_chain {
 	(
@@ -42,11 +1288,16 @@ macro_rules! decl_test_relay_chain {
 			Runtime = $runtime:path,
 			XcmConfig = $xcm_config:path,
 			new_ext = $new_ext:expr,
+			$( AllPalletsWithoutSystem = $all_pallets:path, )?
+			$( LocationToAccountId = $location_to_account:path, )?
+			$( Migrations = $migrations:path, )?
 		}
 	) => {
 		pub struct $name;
 
-		$crate::__impl_ext_for_relay_chain!($name, $runtime, $new_ext);
+		$crate::__impl_ext_for_relay_chain!(
+			$name, $runtime, $new_ext, [$($all_pallets)?], [$($location_to_account)?], [$($migrations)?]
+		);
 
 		impl $crate::UmpSink for $name {
 			fn process_upward_message(
@@ -56,11 +1307,79 @@ macro_rules! decl_test_relay_chain {
 			) -> Result<$crate::Weight, ($crate::MessageId, $crate::Weight)> {
 				use $crate::{TestExt, UmpSink};
 
-				Self::execute_with(|| {
-					$crate::XcmSink::<$crate::XcmExecutor<$xcm_config>, $runtime>::process_upward_message(
+				let result = Self::execute_with(|| {
+					let since = $crate::frame_system::Pallet::<$runtime>::event_count();
+					let result = $crate::XcmSink::<$crate::XcmExecutor<$xcm_config>, $runtime>::process_upward_message(
 						origin, msg, max_weight,
-					)
-				})
+					);
+					let executed =
+						_record_executions($crate::ChainId::Relay, $crate::relay_executed_messages::<$runtime>(since));
+					// downward messages are sent on after this closure, all of them by this message
+					_RELAY_CAUSE.with(|b| b.replace(executed.last().copied()));
+					result
+				});
+				_RELAY_CAUSE.with(|b| b.replace(None));
+				result
+			}
+		}
+
+		impl $name {
+			/// Universal location of the relay chain, as declared by its `XcmConfig`.
+			pub fn universal_location() -> $crate::InteriorMultiLocation {
+				use $crate::Get;
+
+				$crate::paste::paste! {
+					[<EXT_ $name:upper>].with(|v| {
+						v.borrow_mut().execute_with(|| {
+							<<$xcm_config as $crate::xcm_executor::Config>::UniversalLocation as Get<_>>::get()
+						})
+					})
+				}
+			}
+
+			/// Network of the relay chain consensus, if its universal location is a global consensus.
+			pub fn global_consensus() -> Option<$crate::NetworkId> {
+				match Self::universal_location() {
+					$crate::X1($crate::GlobalConsensus(network)) => Some(network),
+					_ => None,
+				}
+			}
+
+			/// Misconfigurations of the relay chain: its host configuration and universal location.
+			fn misconfigurations() -> Vec<$crate::Misconfiguration> {
+				use $crate::frame_support::{storage::migration::have_storage_value, traits::PalletInfo};
+
+				let chain = stringify!($name);
+				let mut misconfigurations = Vec::new();
+
+				$crate::paste::paste! {
+					[<EXT_ $name:upper>].with(|v| {
+						v.borrow_mut().execute_with(|| {
+							let has_config = <<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+								$crate::configuration::Pallet<$runtime>,
+							>()
+							.map_or(false, |name| have_storage_value(name.as_bytes(), b"ActiveConfig", &[]));
+
+							if !has_config {
+								misconfigurations.push($crate::Misconfiguration::MissingHostConfiguration { chain });
+							} else if let Err(error) = $crate::configuration::Pallet::<$runtime>::config().check_consistency() {
+								misconfigurations.push($crate::Misconfiguration::InconsistentHostConfiguration {
+									chain,
+									error: format!("{:?}", error),
+								});
+							}
+						})
+					})
+				}
+
+				if Self::global_consensus().is_none() {
+					misconfigurations.push($crate::Misconfiguration::InvalidRelayUniversalLocation {
+						chain,
+						actual: Self::universal_location(),
+					});
+				}
+
+				misconfigurations
 			}
 		}
 	};
@@ -75,11 +1394,18 @@ macro_rules! decl_test_parachain {
 			XcmpMessageHandler = $xcmp_message_handler:path,
 			DmpMessageHandler = $dmp_message_handler:path,
 			new_ext = $new_ext:expr,
+			$( XcmConfig = $xcm_config:path, )?
+			$( AllPalletsWithoutSystem = $all_pallets:path, )?
+			$( LocationToAccountId = $location_to_account:path, )?
+			$( Migrations = $migrations:path, )?
 		}
 	) => {
 		pub struct $name;
 
-		$crate::__impl_ext_for_parachain!($name, $runtime, $origin, $new_ext);
+		$crate::__impl_ext_for_parachain!(
+			$name, $runtime, $origin, $new_ext, [$($all_pallets)?], [$($location_to_account)?],
+			[$($migrations)?]
+		);
 
 		impl $crate::XcmpMessageHandler for $name {
 			fn handle_xcmp_messages<'a, I: Iterator<Item = ($crate::ParaId, $crate::RelayBlockNumber, &'a [u8])>>(
@@ -88,7 +1414,12 @@ macro_rules! decl_test_parachain {
 			) -> $crate::Weight {
 				use $crate::{TestExt, XcmpMessageHandler};
 
-				$name::execute_with(|| <$xcmp_message_handler>::handle_xcmp_messages(iter, max_weight))
+				$name::execute_with(|| {
+					let since = $crate::frame_system::Pallet::<$runtime>::event_count();
+					let weight = <$xcmp_message_handler>::handle_xcmp_messages(iter, max_weight);
+					$name::record_executions(since);
+					weight
+				})
 			}
 		}
 
@@ -99,27 +1430,522 @@ macro_rules! decl_test_parachain {
 			) -> $crate::Weight {
 				use $crate::{DmpMessageHandler, TestExt};
 
-				$name::execute_with(|| <$dmp_message_handler>::handle_dmp_messages(iter, max_weight))
+				let messages = iter.collect::<Vec<_>>();
+				$name::extend_dmq_mqc(&messages);
+				$name::execute_with(|| {
+					let since = $crate::frame_system::Pallet::<$runtime>::event_count();
+					let weight = <$dmp_message_handler>::handle_dmp_messages(messages.into_iter(), max_weight);
+					$name::record_executions(since);
+					weight
+				})
 			}
 		}
-	};
-}
+
+		impl $name {
+			/// Record in the network journal the outcomes of messages executed since the event `since` of
+			/// the block, as reported by the message handlers of the parachain. Must be called in the
+			/// parachain externalities.
+			fn record_executions(since: u32) {
+				use $crate::Get;
+
+				type Reports = (
+					$crate::cumulus_pallet_parachain_system::Pallet<$runtime>,
+					$dmp_message_handler,
+					$xcmp_message_handler,
+				);
+				let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
+				_record_executions(
+					$crate::ChainId::Parachain(para_id.into()),
+					$crate::parachain_executed_messages::<$runtime, Reports>(since),
+				);
+			}
+
+			/// Misconfigurations of the parachain declared as `para_id` in a network of `relay_network`:
+			/// its `parachain_info` id, and the universal location of its `XcmConfig`, or of its
+			/// `pallet_xcm` if `XcmConfig` isn't declared.
+			fn misconfigurations(
+				para_id: u32,
+				relay_network: Option<$crate::NetworkId>,
+			) -> Vec<$crate::Misconfiguration> {
+				use $crate::Parachain;
+
+				let chain = stringify!($name);
+				let mut misconfigurations = Vec::new();
+
+				let actual = u32::from(Self::para_id());
+				if actual != para_id {
+					misconfigurations.push($crate::Misconfiguration::ParaIdMismatch { chain, declared: para_id, actual });
+				}
+
+				type UniversalLocation = $crate::__or_default!(
+					[$(<$xcm_config as $crate::xcm_executor::Config>::UniversalLocation)?]
+					<$runtime as $crate::pallet_xcm::Config>::UniversalLocation
+				);
+				let actual = $crate::paste::paste! {
+					[<EXT_ $name:upper>].with(|v| {
+						v.borrow_mut().execute_with(|| <UniversalLocation as $crate::Get<_>>::get())
+					})
+				};
+				if let Some(network) = relay_network {
+					let expected = $crate::X2($crate::GlobalConsensus(network), $crate::Junction::Parachain(para_id));
+					if actual != expected {
+						misconfigurations.push($crate::Misconfiguration::UniversalLocationMismatch { chain, expected, actual });
+					}
+				}
+
+				misconfigurations
+			}
+		}
+	};
+}
+
+/// The item declared in the brackets, or the default after them if none is declared.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __or_default {
+	([] $($default:tt)*) => {
+		$($default)*
+	};
+	([$($declared:tt)+] $($default:tt)*) => {
+		$($declared)+
+	};
+}
 
 #[macro_export]
 macro_rules! __impl_ext_for_relay_chain {
 	// entry point: generate ext name
-	($name:ident, $runtime:path, $new_ext:expr) => {
+	(
+		$name:ident, $runtime:path, $new_ext:expr, [$($all_pallets:path)?], [$($location_to_account:path)?],
+		[$($migrations:path)?]
+	) => {
 		$crate::paste::paste! {
-			$crate::__impl_ext_for_relay_chain!(@impl $name, $runtime, $new_ext, [<EXT_ $name:upper>]);
+			$crate::__impl_ext_for_relay_chain!(
+				@impl $name, $runtime, $new_ext, [$($all_pallets)?], [$($location_to_account)?], [$($migrations)?],
+				[<EXT_ $name:upper>]
+			);
 		}
 	};
 	// impl
-	(@impl $name:ident, $runtime:path, $new_ext:expr, $ext_name:ident) => {
+	(
+		@impl $name:ident, $runtime:path, $new_ext:expr, [$($all_pallets:path)?], [$($location_to_account:path)?],
+		[$($migrations:path)?], $ext_name:ident
+	) => {
 		thread_local! {
 			pub static $ext_name: $crate::RefCell<$crate::TestExternalities>
 				= $crate::RefCell::new($new_ext);
 		}
 
+		impl $crate::Chain for $name {
+			type Runtime = $runtime;
+			type RuntimeCall = <$runtime as $crate::frame_system::Config>::RuntimeCall;
+			type RuntimeOrigin = <$runtime as $crate::frame_system::Config>::RuntimeOrigin;
+			type RuntimeEvent = <$runtime as $crate::frame_system::Config>::RuntimeEvent;
+
+			fn events() -> Vec<Self::RuntimeEvent> {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						$crate::frame_system::Pallet::<$runtime>::events().into_iter().map(|r| r.event).collect()
+					})
+				})
+			}
+
+			fn sovereign_account_id_of(location: $crate::MultiLocation) -> $crate::AccountIdOf<$runtime> {
+				use $crate::Convert;
+				type AccountId = $crate::AccountIdOf<$runtime>;
+				type LocationToAccountId = $crate::__or_default!(
+					[$($location_to_account)?]
+					(
+						$crate::ChildParachainConvertsVia<$crate::ParaId, AccountId>,
+						$crate::AccountId32Aliases<(), AccountId>,
+					)
+				);
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						LocationToAccountId::convert_ref(&location)
+							.unwrap_or_else(|_| panic!("no account for location {:?}", location))
+					})
+				})
+			}
+		}
+
+		impl $crate::RelayChain for $name {}
+
+		impl $name {
+			/// The active host configuration of the relay chain.
+			pub fn host_configuration() -> $crate::HostConfiguration<$crate::RelayBlockNumber> {
+				$ext_name.with(|v| {
+					v.borrow_mut()
+						.execute_with(|| $crate::configuration::Pallet::<$runtime>::config())
+				})
+			}
+
+			/// Upward messages in the overweight queue of the `ump` pallet.
+			pub fn overweight_upward_messages() -> Vec<$crate::OverweightMessage> {
+				use $crate::frame_support::traits::PalletInfo;
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+							$crate::ump::Pallet<$runtime>,
+						>()
+						.map($crate::overweight_ump_messages)
+						.unwrap_or_default()
+					})
+				})
+			}
+
+			/// Execute the overweight upward message `index` with at most `weight_limit`, as root.
+			pub fn service_overweight_upward_message(
+				index: u64,
+				weight_limit: $crate::Weight,
+			) -> $crate::frame_support::dispatch::DispatchResultWithPostInfo {
+				use $crate::TestExt;
+
+				Self::execute_with(|| {
+					$crate::ump::Pallet::<$runtime>::service_overweight(
+						$crate::frame_system::RawOrigin::Root.into(),
+						index,
+						weight_limit,
+					)
+				})
+			}
+
+			/// Keep an upward message requiring `required` weight in the overweight queue of the `ump`
+			/// pallet, as the pallet does for messages exceeding `ump_max_individual_weight`.
+			fn stash_overweight_upward_message(
+				origin: $crate::ParaId,
+				msg: Vec<u8>,
+				id: $crate::MessageId,
+				required: $crate::Weight,
+			) {
+				use $crate::{frame_support::traits::PalletInfo, TestExt};
+
+				Self::execute_with(|| {
+					let name = <<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+						$crate::ump::Pallet<$runtime>,
+					>()
+					.expect("ump pallet is in the runtime; qed");
+					let index = $crate::stash_overweight_ump_message(name, origin, msg);
+
+					$crate::frame_system::Pallet::<$runtime>::deposit_event(
+						<$runtime as $crate::ump::Config>::RuntimeEvent::from(
+							$crate::ump::Event::<$runtime>::OverweightEnqueued(origin, id, index, required),
+						)
+						.into(),
+					);
+				})
+			}
+
+			/// Onboard or offboard `para_id` in the `paras` pallet. Downward messages to the parachain and
+			/// their MQC head are cleaned up either way, as they would be on a session change.
+			fn set_parachain_registered(para_id: $crate::ParaId, registered: bool) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						$crate::set_parachain_registered(Self::paras_pallet_name(), para_id, registered);
+						$crate::clean_dmq(Self::dmp_pallet_name(), para_id);
+					})
+				})
+			}
+
+			/// Name of the `paras` pallet in the relay chain runtime. Must be called in the relay chain
+			/// externalities.
+			fn paras_pallet_name() -> &'static str {
+				use $crate::frame_support::traits::PalletInfo;
+
+				<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+					$crate::paras::Pallet<$runtime>,
+				>()
+				.expect("relay chain runtime must include the paras pallet")
+			}
+
+			/// Name of the `dmp` pallet in the relay chain runtime. Must be called in the relay chain
+			/// externalities.
+			fn dmp_pallet_name() -> &'static str {
+				use $crate::frame_support::traits::PalletInfo;
+
+				<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+					$crate::dmp::Pallet<$runtime>,
+				>()
+				.expect("relay chain runtime must include the dmp pallet")
+			}
+
+			/// Remove the downward messages to `para_id` and their MQC head from the `dmp` pallet.
+			fn clean_dmq(para_id: $crate::ParaId) {
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| $crate::clean_dmq(Self::dmp_pallet_name(), para_id)))
+			}
+
+			/// Remove the first `processed` downward messages to `para_id` from the `dmp` pallet.
+			fn prune_dmq(para_id: $crate::ParaId, processed: u32) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| $crate::prune_dmq(Self::dmp_pallet_name(), para_id, processed))
+				})
+			}
+
+			/// Code upgrade signals of the relay chain `paras` pallet for `para_id`: `(go_ahead, restriction)`.
+			pub fn upgrade_signals(
+				para_id: $crate::ParaId,
+			) -> (
+				Option<$crate::polkadot_primitives::UpgradeGoAhead>,
+				Option<$crate::polkadot_primitives::UpgradeRestriction>,
+			) {
+				use $crate::frame_support::{storage::migration::get_storage_value, StorageHasher, Twox64Concat};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let name = Self::paras_pallet_name();
+						let key = Twox64Concat::hash(&$crate::Encode::encode(&para_id));
+
+						(
+							get_storage_value(name.as_bytes(), b"UpgradeGoAheadSignal", &key),
+							get_storage_value(name.as_bytes(), b"UpgradeRestrictionSignal", &key),
+						)
+					})
+				})
+			}
+
+			/// Set the upgrade restriction signal of the relay chain `paras` pallet for `para_id`, or clear it
+			/// with `None`.
+			pub fn set_upgrade_restriction(
+				para_id: $crate::ParaId,
+				restriction: Option<$crate::polkadot_primitives::UpgradeRestriction>,
+			) {
+				use $crate::frame_support::{
+					storage::migration::{put_storage_value, take_storage_value},
+					StorageHasher, Twox64Concat,
+				};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let name = Self::paras_pallet_name();
+						let key = Twox64Concat::hash(&$crate::Encode::encode(&para_id));
+
+						match restriction {
+							Some(restriction) => {
+								put_storage_value(name.as_bytes(), b"UpgradeRestrictionSignal", &key, restriction)
+							},
+							None => {
+								take_storage_value::<$crate::polkadot_primitives::UpgradeRestriction>(
+									name.as_bytes(),
+									b"UpgradeRestrictionSignal",
+									&key,
+								);
+							},
+						}
+					})
+				})
+			}
+
+			/// Name of the `hrmp` pallet in the relay chain runtime. Must be called in the relay chain
+			/// externalities.
+			fn hrmp_pallet_name() -> &'static str {
+				use $crate::frame_support::traits::PalletInfo;
+
+				<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+					$crate::hrmp::Pallet<$runtime>,
+				>()
+				.expect("hrmp pallet is in the runtime; qed")
+			}
+
+			/// Open the HRMP channel from `sender` to `recipient` in the `hrmp` pallet, if not open yet.
+			pub fn open_hrmp_channel(sender: $crate::ParaId, recipient: $crate::ParaId, config: $crate::HrmpChannelConfig) {
+				$ext_name.with(|v| {
+					v.borrow_mut()
+						.execute_with(|| $crate::open_hrmp_channel(Self::hrmp_pallet_name(), sender, recipient, config))
+				})
+			}
+
+			/// Close every HRMP channel of `para_id` in the `hrmp` pallet.
+			pub fn close_hrmp_channels(para_id: $crate::ParaId) {
+				$ext_name.with(|v| {
+					v.borrow_mut()
+						.execute_with(|| $crate::close_hrmp_channels(Self::hrmp_pallet_name(), para_id))
+				})
+			}
+
+			/// HRMP channels of `para_id` in the `hrmp` pallet: the recipients of its egress channels, the
+			/// senders of its ingress channels, and the channels.
+			#[allow(clippy::type_complexity)]
+			pub fn hrmp_channels(
+				para_id: $crate::ParaId,
+			) -> (
+				Vec<$crate::ParaId>,
+				Vec<$crate::ParaId>,
+				$crate::BTreeMap<$crate::cumulus_primitives_core::relay_chain::HrmpChannelId, $crate::HrmpChannel>,
+			) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| $crate::hrmp_channels_of(Self::hrmp_pallet_name(), para_id))
+				})
+			}
+
+			/// Messages in the HRMP channel from `sender` to `recipient` of the `hrmp` pallet.
+			pub fn hrmp_channel_contents(
+				sender: $crate::ParaId,
+				recipient: $crate::ParaId,
+			) -> Vec<$crate::InboundHrmpMessage> {
+				use $crate::frame_support::{storage::migration::get_storage_value, StorageHasher, Twox64Concat};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let key = Twox64Concat::hash(&$crate::Encode::encode(
+							&$crate::cumulus_primitives_core::relay_chain::HrmpChannelId { sender, recipient },
+						));
+						get_storage_value(Self::hrmp_pallet_name().as_bytes(), b"HrmpChannelContents", &key)
+							.unwrap_or_default()
+					})
+				})
+			}
+
+			/// HRMP watermark of `para_id` in the `hrmp` pallet: the relay chain block up to which it has
+			/// received horizontal messages.
+			pub fn hrmp_watermark(para_id: $crate::ParaId) -> Option<$crate::RelayBlockNumber> {
+				use $crate::frame_support::{storage::migration::get_storage_value, StorageHasher, Twox64Concat};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let key = Twox64Concat::hash(&$crate::Encode::encode(&para_id));
+						get_storage_value(Self::hrmp_pallet_name().as_bytes(), b"HrmpWatermarks", &key)
+					})
+				})
+			}
+
+			/// Commit horizontal messages from `sender` to `recipient` into the `hrmp` pallet, as included
+			/// in the current block.
+			fn queue_outbound_hrmp(sender: $crate::ParaId, recipient: $crate::ParaId, messages: Vec<Vec<u8>>) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let relay_block = $crate::frame_system::Pallet::<$runtime>::block_number();
+						$crate::queue_outbound_hrmp(Self::hrmp_pallet_name(), sender, recipient, relay_block, messages)
+					})
+				})
+			}
+
+			/// Take the horizontal messages to `recipient` sent up to `watermark` from the `hrmp` pallet.
+			fn prune_hrmp(
+				recipient: $crate::ParaId,
+				watermark: $crate::RelayBlockNumber,
+			) -> $crate::BTreeMap<$crate::ParaId, Vec<$crate::InboundHrmpMessage>> {
+				$ext_name.with(|v| {
+					v.borrow_mut()
+						.execute_with(|| $crate::prune_hrmp(Self::hrmp_pallet_name(), recipient, watermark))
+				})
+			}
+
+			/// Current block number of the relay chain, which is the relay parent number of new parachain
+			/// blocks.
+			pub fn block_number() -> $crate::RelayBlockNumber {
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| $crate::frame_system::Pallet::<$runtime>::block_number()))
+			}
+
+			fn set_block_number(block_number: $crate::RelayBlockNumber) {
+				$ext_name.with(|v| {
+					v.borrow_mut()
+						.execute_with(|| $crate::frame_system::Pallet::<$runtime>::set_block_number(block_number))
+				})
+			}
+
+			/// Schedule an upgrade of the parachain `para_id` code to `code` through the `paras` pallet, as
+			/// the inclusion of a candidate with new validation code built on `relay_parent_number` does.
+			/// As the candidate, the upgrade is rejected with `NewCodeTooLarge` if `code` exceeds
+			/// `max_code_size`, and with `PrematureCodeUpgrade` while an upgrade of the parachain is
+			/// scheduled or restricted.
+			pub fn schedule_code_upgrade(
+				para_id: $crate::ParaId,
+				code: Vec<u8>,
+				relay_parent_number: $crate::RelayBlockNumber,
+			) -> $crate::frame_support::dispatch::DispatchResult {
+				use $crate::frame_support::{
+					ensure, storage::migration::have_storage_value, StorageHasher, Twox64Concat,
+				};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let name = Self::paras_pallet_name();
+						let key = Twox64Concat::hash(&$crate::Encode::encode(&para_id));
+
+						let config = $crate::configuration::Pallet::<$runtime>::config();
+						ensure!(
+							code.len() <= config.max_code_size as usize,
+							$crate::inclusion::Error::<$runtime>::NewCodeTooLarge
+						);
+						ensure!(
+							!have_storage_value(name.as_bytes(), b"FutureCodeHash", &key)
+								&& !have_storage_value(name.as_bytes(), b"UpgradeRestrictionSignal", &key),
+							$crate::inclusion::Error::<$runtime>::PrematureCodeUpgrade
+						);
+
+						$crate::paras::Pallet::<$runtime>::force_schedule_code_upgrade(
+							$crate::frame_system::RawOrigin::Root.into(),
+							para_id,
+							code.into(),
+							relay_parent_number,
+						)
+					})
+				})
+			}
+
+			/// Note a new head of `para_id` in the `paras` pallet, as the inclusion of a candidate does: the
+			/// future code becomes the current code if the upgrade went ahead, and the go-ahead signal is
+			/// removed.
+			fn note_new_head(para_id: $crate::ParaId) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let head = $crate::paras::Pallet::<$runtime>::para_head(para_id).unwrap_or_default();
+						$crate::paras::Pallet::<$runtime>::force_note_new_head(
+							$crate::frame_system::RawOrigin::Root.into(),
+							para_id,
+							head,
+						)
+						.expect("called with the root origin; qed");
+					})
+				})
+			}
+
+			/// Upgrade the relay chain runtime to `code`: `:code` is replaced, and the `on_runtime_upgrade`
+			/// hooks of `Migrations`, `frame_system` and `AllPalletsWithoutSystem` run, if declared.
+			pub fn upgrade_runtime(code: Vec<u8>) {
+				use $crate::frame_support::traits::OnRuntimeUpgrade;
+				type Migrations = $crate::__or_default!([$($migrations)?] ());
+				type AllPallets = $crate::__or_default!([$($all_pallets)?] ());
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						$crate::update_code_in_storage::<$runtime>(&code);
+						<(Migrations, $crate::frame_system::Pallet<$runtime>, AllPallets) as OnRuntimeUpgrade>::on_runtime_upgrade();
+					})
+				});
+			}
+
+			/// Read every key-value pair of the chain storage.
+			pub fn storage_snapshot() -> $crate::StorageSnapshot {
+				$ext_name.with(|v| v.borrow_mut().execute_with($crate::storage_snapshot))
+			}
+
+			/// Replace the chain storage with `snapshot`.
+			pub fn restore_storage(snapshot: &[(Vec<u8>, Vec<u8>)]) {
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| $crate::restore_storage(snapshot)))
+			}
+
+			/// Produce a new block, running `on_initialize` and `on_finalize` hooks of `AllPalletsWithoutSystem`,
+			/// or only of the `paras` pallet if not declared, which signals the go-ahead of parachain code
+			/// upgrades.
+			pub fn produce_block() {
+				use $crate::{
+					frame_support::traits::{OnFinalize, OnInitialize},
+					One, TestExt,
+				};
+				type System = $crate::frame_system::Pallet<$runtime>;
+				type AllPallets = $crate::__or_default!([$($all_pallets)?] $crate::paras::Pallet<$runtime>);
+
+				Self::execute_with(|| {
+					let block_number = System::block_number().saturating_add(One::one());
+					System::initialize(&block_number, &Default::default(), &Default::default());
+					<AllPallets as OnInitialize<_>>::on_initialize(block_number);
+					<AllPallets as OnFinalize<_>>::on_finalize(block_number);
+				});
+			}
+		}
+
 		impl $crate::TestExt for $name {
 			fn new_ext() -> $crate::TestExternalities {
 				$new_ext
@@ -127,6 +1953,7 @@ macro_rules! __impl_ext_for_relay_chain {
 
 			fn reset_ext() {
 				$ext_name.with(|v| *v.borrow_mut() = $new_ext);
+				_purge_relay_chain_messages();
 			}
 
 			fn execute_with<R>(execute: impl FnOnce() -> R) -> R {
@@ -137,16 +1964,36 @@ macro_rules! __impl_ext_for_relay_chain {
 					v.borrow_mut().execute_with(|| {
 						use $crate::polkadot_primitives::runtime_api::runtime_decl_for_parachain_host::ParachainHostV4;
 
-						//TODO: mark sent count & filter out sent msg
+						let dmp_pallet_name = Self::dmp_pallet_name();
+
+						// messages to parachains not registered are dropped
 						for para_id in _para_ids() {
-							// downward messages
-							let downward_messages = <$runtime>::dmq_contents(para_id.into())
-								.into_iter()
-								.map(|inbound| (inbound.sent_at, inbound.msg));
-							if downward_messages.len() == 0 {
+							// downward messages, those already sent stay in the queue until the parachain
+							// processes them
+							let forwarded = _dmq_forwarded(para_id) as usize;
+							let downward_messages =
+								<$runtime>::dmq_contents(para_id.into()).into_iter().skip(forwarded).collect::<Vec<_>>();
+							if downward_messages.is_empty() {
 								continue;
 							}
-							_Messenger::send_downward_messages(para_id, downward_messages.into_iter());
+
+							// blocks of the parachain prove the MQC head at their relay parent, offboarded parachains
+							// have no queue
+							if _is_registered(para_id) {
+								let sent_at = downward_messages.last().map_or(0, |inbound| inbound.sent_at);
+								let head = $crate::dmq_mqc_head(dmp_pallet_name, para_id.into());
+								_note_dmq_mqc_head(para_id, sent_at, head);
+								_DMQ_FORWARDED.with(|b| {
+									*b.borrow_mut().entry(para_id).or_default() += downward_messages.len() as u32
+								});
+							} else {
+								$crate::clean_dmq(dmp_pallet_name, para_id.into());
+								_DMQ_FORWARDED.with(|b| b.borrow_mut().remove(&para_id));
+							}
+							_Messenger::send_downward_messages(
+								para_id,
+								downward_messages.into_iter().map(|inbound| (inbound.sent_at, inbound.msg)),
+							);
 
 							// Note: no need to handle horizontal messages, as the
 							// simulator directly sends them to dest (not relayed).
@@ -165,37 +2012,397 @@ macro_rules! __impl_ext_for_relay_chain {
 #[macro_export]
 macro_rules! __impl_ext_for_parachain {
 	// entry point: generate ext name
-	($name:ident, $runtime:path, $origin:path, $new_ext:expr) => {
+	(
+		$name:ident, $runtime:path, $origin:path, $new_ext:expr, [$($all_pallets:path)?],
+		[$($location_to_account:path)?], [$($migrations:path)?]
+	) => {
 		$crate::paste::paste! {
-			$crate::__impl_ext_for_parachain!(@impl $name, $runtime, $origin, $new_ext, [<EXT_ $name:upper>]);
+			$crate::__impl_ext_for_parachain!(
+				@impl $name, $runtime, $origin, $new_ext, [$($all_pallets)?], [$($location_to_account)?],
+				[$($migrations)?], [<EXT_ $name:upper>]
+			);
 		}
 	};
 	// impl
-	(@impl $name:ident, $runtime:path, $origin:path, $new_ext:expr, $ext_name:ident) => {
+	(
+		@impl $name:ident, $runtime:path, $origin:path, $new_ext:expr, [$($all_pallets:path)?],
+		[$($location_to_account:path)?], [$($migrations:path)?], $ext_name:ident
+	) => {
 		thread_local! {
 			pub static $ext_name: $crate::RefCell<$crate::TestExternalities>
 				= $crate::RefCell::new($new_ext);
 		}
 
+		impl $crate::Chain for $name {
+			type Runtime = $runtime;
+			type RuntimeCall = <$runtime as $crate::frame_system::Config>::RuntimeCall;
+			type RuntimeOrigin = $origin;
+			type RuntimeEvent = <$runtime as $crate::frame_system::Config>::RuntimeEvent;
+
+			fn events() -> Vec<Self::RuntimeEvent> {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						$crate::frame_system::Pallet::<$runtime>::events().into_iter().map(|r| r.event).collect()
+					})
+				})
+			}
+
+			fn sovereign_account_id_of(location: $crate::MultiLocation) -> $crate::AccountIdOf<$runtime> {
+				use $crate::Convert;
+				type AccountId = $crate::AccountIdOf<$runtime>;
+				type LocationToAccountId = $crate::__or_default!(
+					[$($location_to_account)?]
+					(
+						$crate::ParentIsPreset<AccountId>,
+						$crate::SiblingParachainConvertsVia<$crate::Sibling, AccountId>,
+						$crate::AccountId32Aliases<(), AccountId>,
+					)
+				);
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						LocationToAccountId::convert_ref(&location)
+							.unwrap_or_else(|_| panic!("no account for location {:?}", location))
+					})
+				})
+			}
+		}
+
+		impl $crate::Parachain for $name {
+			fn para_id() -> $crate::ParaId {
+				use $crate::Get;
+
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| $crate::parachain_info::Pallet::<$runtime>::get()))
+			}
+		}
+
 		impl $name {
+			/// Downward messages received but not executed yet due to the weight limit.
+			pub fn deferred_downward_messages() -> Vec<($crate::RelayBlockNumber, Vec<u8>)> {
+				use $crate::frame_support::traits::PalletInfo;
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+							$crate::cumulus_pallet_dmp_queue::Pallet<$runtime>,
+						>()
+						.map($crate::deferred_dmp_queue_messages)
+						.unwrap_or_default()
+					})
+				})
+			}
+
+			/// Horizontal messages received but not executed yet due to the weight limit.
+			pub fn deferred_horizontal_messages() -> Vec<($crate::ParaId, $crate::RelayBlockNumber, Vec<u8>)> {
+				use $crate::frame_support::traits::PalletInfo;
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+							$crate::cumulus_pallet_xcmp_queue::Pallet<$runtime>,
+						>()
+						.map($crate::deferred_xcmp_queue_messages)
+						.unwrap_or_default()
+					})
+				})
+			}
+
+			/// Downward and horizontal messages in the overweight queues of the parachain.
+			pub fn overweight_messages() -> Vec<$crate::OverweightMessage> {
+				use $crate::{frame_support::traits::PalletInfo, Get};
+				type PalletInfoOf<T> = <T as $crate::frame_system::Config>::PalletInfo;
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let para_id: u32 = $crate::parachain_info::Pallet::<$runtime>::get().into();
+
+						let mut messages = <PalletInfoOf<$runtime> as PalletInfo>::name::<
+							$crate::cumulus_pallet_dmp_queue::Pallet<$runtime>,
+						>()
+						.map(|name| $crate::overweight_dmp_queue_messages(name, para_id))
+						.unwrap_or_default();
+						messages.extend(
+							<PalletInfoOf<$runtime> as PalletInfo>::name::<
+								$crate::cumulus_pallet_xcmp_queue::Pallet<$runtime>,
+							>()
+							.map(|name| $crate::overweight_xcmp_queue_messages(name, para_id))
+							.unwrap_or_default(),
+						);
+						messages
+					})
+				})
+			}
+
+			/// Execute the overweight downward message `index` with at most `weight_limit`, as root.
+			pub fn service_overweight_downward_message(
+				index: u64,
+				weight_limit: $crate::Weight,
+			) -> $crate::frame_support::dispatch::DispatchResultWithPostInfo {
+				use $crate::TestExt;
+
+				Self::execute_with(|| {
+					$crate::cumulus_pallet_dmp_queue::Pallet::<$runtime>::service_overweight(
+						$crate::frame_system::RawOrigin::Root.into(),
+						index,
+						weight_limit,
+					)
+				})
+			}
+
+			/// Execute the overweight horizontal message `index` with at most `weight_limit`, as root.
+			pub fn service_overweight_horizontal_message(
+				index: u64,
+				weight_limit: $crate::Weight,
+			) -> $crate::frame_support::dispatch::DispatchResultWithPostInfo {
+				use $crate::TestExt;
+
+				Self::execute_with(|| {
+					$crate::cumulus_pallet_xcmp_queue::Pallet::<$runtime>::service_overweight(
+						$crate::frame_system::RawOrigin::Root.into(),
+						index,
+						weight_limit,
+					)
+				})
+			}
+
+			fn reserved_dmp_weight() -> $crate::Weight {
+				use $crate::Get;
+				<$runtime as $crate::cumulus_pallet_parachain_system::Config>::ReservedDmpWeight::get()
+			}
+
+			fn reserved_xcmp_weight() -> $crate::Weight {
+				use $crate::Get;
+				<$runtime as $crate::cumulus_pallet_parachain_system::Config>::ReservedXcmpWeight::get()
+			}
+
+			/// Relay parent number of the last block.
+			pub fn last_relay_parent_number() -> $crate::RelayBlockNumber {
+				use $crate::frame_support::{storage::migration::get_storage_value, traits::PalletInfo};
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+							$crate::cumulus_pallet_parachain_system::Pallet<$runtime>,
+						>()
+						.and_then(|name| get_storage_value(name.as_bytes(), b"LastRelayChainBlockNumber", &[]))
+						.unwrap_or_default()
+					})
+				})
+			}
+
+			/// Relay parent number of the next block, which must be greater than the last one, or not less
+			/// than it under async backing.
+			fn next_relay_parent_number() -> $crate::RelayBlockNumber {
+				_next_relay_parent_number(
+					<Self as $crate::Parachain>::para_id().into(),
+					Self::last_relay_parent_number(),
+				)
+			}
+
 			fn prepare_for_xcmp() {
+				let relay_block = Self::next_relay_parent_number();
+
 				$ext_name.with(|v| {
 					v.borrow_mut().execute_with(|| {
-						use $crate::{Get, Hooks};
+						use $crate::Hooks;
 						type ParachainSystem = $crate::cumulus_pallet_parachain_system::Pallet<$runtime>;
 
 						let block_number = $crate::frame_system::Pallet::<$runtime>::block_number();
-						let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
 
-						let _ = ParachainSystem::set_validation_data(
-							<$origin>::none(),
-							_hrmp_channel_parachain_inherent_data(para_id.into(), 1),
-						);
+						Self::set_validation_data(relay_block);
 						// set `AnnouncedHrmpMessagesPerCandidate`
 						ParachainSystem::on_initialize(block_number);
 					})
 				});
 			}
+
+			/// Produce a new block, running `on_initialize` and `on_finalize` hooks of `AllPalletsWithoutSystem`,
+			/// or only of `cumulus_pallet_parachain_system` if not declared.
+			pub fn produce_block() {
+				Self::build_block();
+
+				_process_messages();
+			}
+
+			fn build_block() {
+				use $crate::{
+					frame_support::traits::{OnFinalize, OnInitialize},
+					Hooks, One,
+				};
+				type System = $crate::frame_system::Pallet<$runtime>;
+				type ParachainSystem = $crate::cumulus_pallet_parachain_system::Pallet<$runtime>;
+				type AllPallets = $crate::__or_default!(
+					[$($all_pallets)?] $crate::cumulus_pallet_parachain_system::Pallet<$runtime>
+				);
+
+				let relay_block = Self::next_relay_parent_number();
+
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let block_number = System::block_number().saturating_add(One::one());
+						System::initialize(&block_number, &Default::default(), &Default::default());
+						<AllPallets as OnInitialize<_>>::on_initialize(block_number);
+
+						Self::set_validation_data(relay_block);
+
+						<AllPallets as OnFinalize<_>>::on_finalize(block_number);
+						Self::send_collation_messages(relay_block);
+
+						// clean messages
+						ParachainSystem::on_initialize(block_number);
+					})
+				});
+			}
+
+			/// Set validation data as the `set_validation_data` inherent does, which also services the
+			/// inbound message queues. Must be called in the parachain externalities.
+			fn set_validation_data(relay_block: $crate::RelayBlockNumber) {
+				use $crate::Get;
+				type ParachainSystem = $crate::cumulus_pallet_parachain_system::Pallet<$runtime>;
+
+				let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
+				let (_, hrmp_mqc_heads) = Self::last_mqc_heads();
+				let code = $crate::frame_support::storage::unhashed::get_raw(b":code");
+				let since = $crate::frame_system::Pallet::<$runtime>::event_count();
+				let _ = ParachainSystem::set_validation_data(
+					<$origin>::none(),
+					_parachain_inherent_data(para_id.into(), relay_block, hrmp_mqc_heads),
+				);
+				Self::record_executions(since);
+
+				// the pending code upgrade was applied on the go-ahead of the relay chain
+				if $crate::frame_support::storage::unhashed::get_raw(b":code") != code {
+					Self::on_runtime_upgrade();
+				}
+			}
+
+			/// Schedule an upgrade of the parachain runtime to `code` through `ParachainSetCode`, as
+			/// `set_code` does without checking the runtime version.
+			///
+			/// The upgrade is set in a block of the parachain, which needs the validation data of the block,
+			/// and the code is sent to the relay chain with this block to be scheduled by the `paras` pallet.
+			/// Once `validation_upgrade_delay` is over, the go-ahead signal is in the relay chain state
+			/// proof, and the parachain applies the code in its next block, running the `on_runtime_upgrade`
+			/// hooks.
+			pub fn schedule_runtime_upgrade(code: Vec<u8>) -> $crate::frame_support::dispatch::DispatchResult {
+				use $crate::{frame_system::SetCode, TestExt};
+
+				Self::execute_with(|| $crate::cumulus_pallet_parachain_system::ParachainSetCode::<$runtime>::set_code(code))
+			}
+
+			/// Read every key-value pair of the chain storage.
+			pub fn storage_snapshot() -> $crate::StorageSnapshot {
+				$ext_name.with(|v| v.borrow_mut().execute_with($crate::storage_snapshot))
+			}
+
+			/// Replace the chain storage with `snapshot`.
+			pub fn restore_storage(snapshot: &[(Vec<u8>, Vec<u8>)]) {
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| $crate::restore_storage(snapshot)))
+			}
+
+			/// Run the `on_runtime_upgrade` hooks of `Migrations`, `frame_system` and `AllPalletsWithoutSystem`,
+			/// or only of `cumulus_pallet_parachain_system` if not declared. Must be called in the parachain
+			/// externalities.
+			fn on_runtime_upgrade() {
+				use $crate::frame_support::traits::OnRuntimeUpgrade;
+				type Migrations = $crate::__or_default!([$($migrations)?] ());
+				type AllPallets = $crate::__or_default!(
+					[$($all_pallets)?] $crate::cumulus_pallet_parachain_system::Pallet<$runtime>
+				);
+
+				<(Migrations, $crate::frame_system::Pallet<$runtime>, AllPallets) as OnRuntimeUpgrade>::on_runtime_upgrade();
+			}
+
+			/// MQC heads of the last downward and horizontal messages the parachain received in an
+			/// inherent. Must be called in the parachain externalities.
+			fn last_mqc_heads() -> (
+				$crate::MessageQueueChain,
+				$crate::BTreeMap<$crate::ParaId, $crate::MessageQueueChain>,
+			) {
+				use $crate::frame_support::{storage::migration::get_storage_value, traits::PalletInfo};
+
+				<<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+					$crate::cumulus_pallet_parachain_system::Pallet<$runtime>,
+				>()
+				.map(|name| {
+					(
+						get_storage_value(name.as_bytes(), b"LastDmqMqcHead", &[]).unwrap_or_default(),
+						get_storage_value(name.as_bytes(), b"LastHrmpMqcHeads", &[]).unwrap_or_default(),
+					)
+				})
+				.unwrap_or_default()
+			}
+
+			/// Set the MQC head of the last downward messages the parachain received. Must be called in the
+			/// parachain externalities.
+			fn set_last_dmq_mqc_head(head: $crate::MessageQueueChain) {
+				use $crate::frame_support::{storage::migration::put_storage_value, traits::PalletInfo};
+
+				if let Some(name) = <<$runtime as $crate::frame_system::Config>::PalletInfo as PalletInfo>::name::<
+					$crate::cumulus_pallet_parachain_system::Pallet<$runtime>,
+				>() {
+					put_storage_value(name.as_bytes(), b"LastDmqMqcHead", &[], head);
+				}
+			}
+
+			/// Start the downward message queue chain over, as a parachain onboarded with an empty queue.
+			fn restart_dmq_mqc() {
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| Self::set_last_dmq_mqc_head(Default::default())));
+			}
+
+			/// Advance the downward message queue chain by `messages` handed to the parachain directly, as
+			/// if they were received in an inherent.
+			fn extend_dmq_mqc(messages: &[($crate::RelayBlockNumber, Vec<u8>)]) {
+				$ext_name.with(|v| {
+					v.borrow_mut().execute_with(|| {
+						let (mut dmq_mqc_head, _) = Self::last_mqc_heads();
+						for (sent_at, msg) in messages {
+							dmq_mqc_head
+								.extend_downward(&$crate::InboundDownwardMessage { sent_at: *sent_at, msg: msg.clone() });
+						}
+						Self::set_last_dmq_mqc_head(dmq_mqc_head);
+					})
+				})
+			}
+
+			/// Send messages of the block to the network. Must be called in the parachain externalities
+			/// after `on_finalize`.
+			fn send_collation_messages(relay_block: $crate::RelayBlockNumber) {
+				use $crate::Get;
+				use sp_runtime::traits::Header as HeaderT;
+				type ParachainSystem = $crate::cumulus_pallet_parachain_system::Pallet<$runtime>;
+
+				let mock_header = HeaderT::new(
+					0,
+					Default::default(),
+					Default::default(),
+					Default::default(),
+					Default::default(),
+				);
+
+				// get messages
+				let collation_info = ParachainSystem::collect_collation_info(&mock_header);
+
+				// send upward messages
+				let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
+				_Messenger::send_upward_messages(para_id.into(), relay_block, collation_info.upward_messages.clone());
+
+				// send horizontal messages
+				for msg in collation_info.horizontal_messages {
+					_Messenger::send_horizontal_messages(
+						msg.recipient.into(),
+						vec![(para_id.into(), relay_block, msg.data)].into_iter(),
+					);
+				}
+
+				// send new validation code
+				if let Some(code) = collation_info.new_validation_code {
+					_schedule_code_upgrade(para_id.into(), code.0, relay_block);
+				}
+
+				// prune downward messages received in the block
+				_prune_dmq(para_id.into(), collation_info.processed_downward_messages);
+			}
 		}
 
 		impl $crate::TestExt for $name {
@@ -205,247 +2412,1571 @@ macro_rules! __impl_ext_for_parachain {
 
 			fn reset_ext() {
 				$ext_name.with(|v| *v.borrow_mut() = $new_ext);
+				_purge_parachain_messages(<Self as $crate::Parachain>::para_id().into());
 			}
 
 			fn execute_with<R>(execute: impl FnOnce() -> R) -> R {
-				use $crate::{Get, Hooks};
+				use $crate::Hooks;
 				type ParachainSystem = $crate::cumulus_pallet_parachain_system::Pallet<$runtime>;
 
-				$crate::GLOBAL_RELAY.with(|v| {
-					*v.borrow_mut() += 1;
-				});
+				// an on-demand parachain only produces a block when it's ordered, so messages stay queued
+				if _is_on_demand(<Self as $crate::Parachain>::para_id().into()) {
+					return $ext_name.with(|v| v.borrow_mut().execute_with(execute));
+				}
 
-				$ext_name.with(|v| {
-					v.borrow_mut().execute_with(|| {
-						let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
-						$crate::GLOBAL_RELAY.with(|v| {
-							let relay_block = *v.borrow();
-							let _ = ParachainSystem::set_validation_data(
-								<$origin>::none(),
-								_hrmp_channel_parachain_inherent_data(para_id.into(), relay_block),
-							);
-						});
-					})
-				});
+				let relay_block = Self::next_relay_parent_number();
+
+				$ext_name.with(|v| v.borrow_mut().execute_with(|| Self::set_validation_data(relay_block)));
 
 				let r = $ext_name.with(|v| v.borrow_mut().execute_with(execute));
 
 				// send messages if needed
 				$ext_name.with(|v| {
 					v.borrow_mut().execute_with(|| {
-						use sp_runtime::traits::Header as HeaderT;
-
 						let block_number = $crate::frame_system::Pallet::<$runtime>::block_number();
-						let mock_header = HeaderT::new(
-							0,
-							Default::default(),
-							Default::default(),
-							Default::default(),
-							Default::default(),
-						);
 
-						// get messages
-						ParachainSystem::on_finalize(block_number);
-						let collation_info = ParachainSystem::collect_collation_info(&mock_header);
+						ParachainSystem::on_finalize(block_number);
+						Self::send_collation_messages(relay_block);
+
+						// clean messages
+						ParachainSystem::on_initialize(block_number);
+					})
+				});
+
+				_process_messages();
+
+				r
+			}
+		}
+	};
+}
+
+thread_local! {
+	/// Messages exported by bridge hubs, by the network they are exported to, waiting to be relayed.
+	pub static BRIDGED_MESSAGES: RefCell<BTreeMap<NetworkId, VecDeque<BridgeMessage>>> = RefCell::new(BTreeMap::new());
+	/// Messages relayed by bridges, by the network they are relayed to.
+	pub static RELAYED_BRIDGE_MESSAGES: RefCell<BTreeMap<NetworkId, Vec<RelayedBridgeMessage>>> =
+		RefCell::new(BTreeMap::new());
+}
+
+#[macro_export]
+macro_rules! decl_test_network {
+	(
+		pub struct $name:ident {
+			relay_chain = $relay_chain:ty,
+			parachains = vec![ $( ($para_id:expr, $parachain:ty), )* ],
+			$( hrmp_channels = vec![ $( ($sender:expr, $recipient:expr $(, $channel_config:expr)?), )* ], )?
+			$( bridge = $bridge:path, )?
+		}
+	) => {
+		pub struct $name;
+
+		thread_local! {
+			/// Downward messages, each message is: `(to_para_id, [(relay_block_number, msg)])`
+			#[allow(clippy::type_complexity)]
+			static _DOWNWARD_MESSAGES: $crate::RefCell<$crate::VecDeque<(u32, Vec<($crate::RelayBlockNumber, Vec<u8>)>)>>
+				= $crate::RefCell::new($crate::VecDeque::new());
+			/// Number of downward messages processed by parachains, each item is: `(para_id, count)`
+			static _DMP_PROCESSED: $crate::RefCell<$crate::BTreeMap<u32, u32>> = $crate::RefCell::new($crate::BTreeMap::new());
+			/// MQC heads of the relay chain DMQs, read from the `dmp` pallet as messages are sent, each item
+			/// is: `(para_id, {sent_at: head})`
+			#[allow(clippy::type_complexity)]
+			static _DMQ_MQC_HEADS: $crate::RefCell<
+				$crate::BTreeMap<u32, $crate::BTreeMap<$crate::RelayBlockNumber, $crate::RelayHash>>,
+			> = $crate::RefCell::new($crate::BTreeMap::new());
+			/// Number of downward messages sent to parachains and not pruned from the relay chain DMQs yet,
+			/// each item is: `(para_id, count)`
+			static _DMQ_FORWARDED: $crate::RefCell<$crate::BTreeMap<u32, u32>> = $crate::RefCell::new($crate::BTreeMap::new());
+			/// Horizontal messages, each message is: `(to_para_id, [(from_para_id, relay_block_number, msg)])`
+			#[allow(clippy::type_complexity)]
+			static _HORIZONTAL_MESSAGES: $crate::RefCell<$crate::VecDeque<(u32, Vec<($crate::ParaId, $crate::RelayBlockNumber, Vec<u8>)>)>>
+				= $crate::RefCell::new($crate::VecDeque::new());
+			/// Upward messages, each message is: `(from_para_id, msg)`
+			static _UPWARD_MESSAGES: $crate::RefCell<$crate::VecDeque<(u32, Vec<u8>)>> = $crate::RefCell::new($crate::VecDeque::new());
+			/// Occupancy of HRMP channels, each item is: `((sender, recipient), (msg_count, total_size))`
+			#[allow(clippy::type_complexity)]
+			static _HRMP_CHANNEL_USAGE: $crate::RefCell<$crate::BTreeMap<(u32, u32), (u32, u32)>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+			/// Horizontal messages handed to parachains, which occupy their channel until the next block of
+			/// the recipient, each item is: `(to_para_id, [(from_para_id, msg_size)])`
+			static _HRMP_DELIVERED: $crate::RefCell<$crate::BTreeMap<u32, Vec<(u32, u32)>>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+			/// Weight used by upward messages in a relay chain block: `(relay_block_number, weight)`
+			static _UMP_WEIGHT_USED: $crate::RefCell<($crate::RelayBlockNumber, $crate::Weight)>
+				= $crate::RefCell::new((0, $crate::Weight::zero()));
+			/// If a message executed with an error fails the test.
+			static _STRICT_MODE: $crate::RefCell<bool> = $crate::RefCell::new(false);
+			/// How downward and horizontal messages are delivered.
+			static _MESSAGE_DELIVERY: $crate::RefCell<$crate::MessageDelivery> = $crate::RefCell::new(Default::default());
+			/// Async backing parameters, if enabled.
+			static _ASYNC_BACKING: $crate::RefCell<Option<$crate::AsyncBackingParams>> = $crate::RefCell::new(None);
+			/// Number of relay chain blocks the relay parents of a parachain lag behind the relay chain head
+			/// under async backing, `(para_id, lag)`.
+			static _RELAY_PARENT_LAG: $crate::RefCell<$crate::BTreeMap<u32, u32>> = $crate::RefCell::new($crate::BTreeMap::new());
+			/// Last relay parent of a parachain under async backing, and the number of blocks built on it,
+			/// `(para_id, (relay_parent_number, blocks))`.
+			#[allow(clippy::type_complexity)]
+			static _RELAY_PARENT_BLOCKS: $crate::RefCell<$crate::BTreeMap<u32, ($crate::RelayBlockNumber, u32)>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+			/// How horizontal messages are routed.
+			static _HRMP_ROUTING: $crate::RefCell<$crate::HrmpRouting> = $crate::RefCell::new(Default::default());
+			/// Messages waiting for the next inherent of a parachain, each item is:
+			/// `(to_para_id, (downward_messages, {from_para_id: horizontal_messages}))`
+			#[allow(clippy::type_complexity)]
+			static _INHERENT_MESSAGES: $crate::RefCell<
+				$crate::BTreeMap<u32, (Vec<$crate::InboundDownwardMessage>, $crate::BTreeMap<$crate::ParaId, Vec<$crate::InboundHrmpMessage>>)>,
+			> = $crate::RefCell::new($crate::BTreeMap::new());
+			/// If messages of the network are being processed.
+			static _PROCESSING_MESSAGES: $crate::RefCell<bool> = $crate::RefCell::new(false);
+			/// Declared parachains offboarded with `deregister_parachain`.
+			static _DEREGISTERED_PARACHAINS: $crate::RefCell<$crate::BTreeSet<u32>>
+				= $crate::RefCell::new($crate::BTreeSet::new());
+			/// Parachains declared on-demand with `set_on_demand`.
+			static _ON_DEMAND_PARACHAINS: $crate::RefCell<$crate::BTreeSet<u32>>
+				= $crate::RefCell::new($crate::BTreeSet::new());
+			/// Blocks ordered for on-demand parachains and not produced yet, `(para_id, count)`.
+			static _ON_DEMAND_ORDERS: $crate::RefCell<$crate::BTreeMap<u32, u32>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+			/// Messages sent between chains of the network since the last reset, in the order they were sent.
+			static _JOURNAL: $crate::RefCell<Vec<$crate::JournalEntry>> = $crate::RefCell::new(Vec::new());
+			/// Journal indices of the messages whose execution sent messages not recorded yet, by sender
+			/// and message id.
+			#[allow(clippy::type_complexity)]
+			static _SENT_BY: $crate::RefCell<$crate::BTreeMap<($crate::ChainId, $crate::XcmHash), Vec<usize>>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+			/// Journal index of the upward message the relay chain executes, which sends the downward
+			/// messages queued in the meantime.
+			static _RELAY_CAUSE: $crate::RefCell<Option<usize>> = $crate::RefCell::new(None);
+			/// Parachain code upgrades rejected by the relay chain since the last reset.
+			static _REJECTED_CODE_UPGRADES: $crate::RefCell<Vec<$crate::RejectedCodeUpgrade>>
+				= $crate::RefCell::new(Vec::new());
+			/// Checkpoints saved with `save_checkpoint`, by name.
+			static _CHECKPOINTS: $crate::RefCell<$crate::BTreeMap<String, _Checkpoint>>
+				= $crate::RefCell::new($crate::BTreeMap::new());
+		}
+
+		/// State of every chain and the message queues of the network.
+		#[derive(Clone)]
+		#[allow(clippy::type_complexity)]
+		struct _Checkpoint {
+			relay_chain: $crate::StorageSnapshot,
+			/// Storage of parachains, in the order they are declared.
+			parachains: Vec<$crate::StorageSnapshot>,
+			downward_messages: $crate::VecDeque<(u32, Vec<($crate::RelayBlockNumber, Vec<u8>)>)>,
+			dmp_processed: $crate::BTreeMap<u32, u32>,
+			dmq_mqc_heads: $crate::BTreeMap<u32, $crate::BTreeMap<$crate::RelayBlockNumber, $crate::RelayHash>>,
+			dmq_forwarded: $crate::BTreeMap<u32, u32>,
+			horizontal_messages: $crate::VecDeque<(u32, Vec<($crate::ParaId, $crate::RelayBlockNumber, Vec<u8>)>)>,
+			upward_messages: $crate::VecDeque<(u32, Vec<u8>)>,
+			hrmp_channel_usage: $crate::BTreeMap<(u32, u32), (u32, u32)>,
+			hrmp_delivered: $crate::BTreeMap<u32, Vec<(u32, u32)>>,
+			ump_weight_used: ($crate::RelayBlockNumber, $crate::Weight),
+			message_delivery: $crate::MessageDelivery,
+			strict_mode: bool,
+			async_backing: Option<$crate::AsyncBackingParams>,
+			relay_parent_lag: $crate::BTreeMap<u32, u32>,
+			relay_parent_blocks: $crate::BTreeMap<u32, ($crate::RelayBlockNumber, u32)>,
+			hrmp_routing: $crate::HrmpRouting,
+			inherent_messages: $crate::BTreeMap<
+				u32,
+				(Vec<$crate::InboundDownwardMessage>, $crate::BTreeMap<$crate::ParaId, Vec<$crate::InboundHrmpMessage>>),
+			>,
+			deregistered_parachains: $crate::BTreeSet<u32>,
+			on_demand_parachains: $crate::BTreeSet<u32>,
+			on_demand_orders: $crate::BTreeMap<u32, u32>,
+			journal: Vec<$crate::JournalEntry>,
+			sent_by: $crate::BTreeMap<($crate::ChainId, $crate::XcmHash), Vec<usize>>,
+			rejected_code_upgrades: Vec<$crate::RejectedCodeUpgrade>,
+			/// Messages bridged to or from the network, and those relayed to it.
+			bridged_messages: (Vec<$crate::BridgeMessage>, Vec<$crate::RelayedBridgeMessage>),
+		}
+
+		impl $name {
+			/// Rebuild every chain from genesis, clear the message queues, and restore the default modes of
+			/// the network: strict mode, message delivery, async backing, relay parent lags, on-demand
+			/// parachains and HRMP routing. Panics with a report of the misconfigurations found by
+			/// `validate`.
+			pub fn reset() {
+				use $crate::{TestExt, VecDeque};
+
+				<$relay_chain>::reset_ext();
+				$( <$parachain>::reset_ext(); )*
+
+				if let Err(report) = Self::validate() {
+					panic!("{}", report);
+				}
+
+				// clear the queues before preparing the parachains, which records their relay parents
+				_DOWNWARD_MESSAGES.with(|b| b.replace(VecDeque::new()));
+				_HORIZONTAL_MESSAGES.with(|b| b.replace(VecDeque::new()));
+				_UPWARD_MESSAGES.with(|b| b.replace(VecDeque::new()));
+				_DMP_PROCESSED.with(|b| b.replace(Default::default()));
+				_DMQ_MQC_HEADS.with(|b| b.replace(Default::default()));
+				_DMQ_FORWARDED.with(|b| b.replace(Default::default()));
+				_HRMP_CHANNEL_USAGE.with(|b| b.replace(Default::default()));
+				_HRMP_DELIVERED.with(|b| b.replace(Default::default()));
+				_UMP_WEIGHT_USED.with(|b| b.replace((0, $crate::Weight::zero())));
+				_PROCESSING_MESSAGES.with(|b| b.replace(false));
+				_INHERENT_MESSAGES.with(|b| b.replace(Default::default()));
+				_ON_DEMAND_ORDERS.with(|b| b.replace(Default::default()));
+				_RELAY_PARENT_BLOCKS.with(|b| b.replace(Default::default()));
+				_JOURNAL.with(|b| b.replace(Vec::new()));
+				_SENT_BY.with(|b| b.replace(Default::default()));
+				_REJECTED_CODE_UPGRADES.with(|b| b.replace(Vec::new()));
+				if let Some(network) = <$relay_chain>::global_consensus() {
+					$crate::clear_bridged_messages(network);
+				}
+
+				_STRICT_MODE.with(|b| b.replace(false));
+				_MESSAGE_DELIVERY.with(|b| b.replace(Default::default()));
+				_ASYNC_BACKING.with(|b| b.replace(None));
+				_RELAY_PARENT_LAG.with(|b| b.replace(Default::default()));
+				_ON_DEMAND_PARACHAINS.with(|b| b.replace(Default::default()));
+				_HRMP_ROUTING.with(|b| b.replace(Default::default()));
+
+				_DEREGISTERED_PARACHAINS.with(|b| b.replace(Default::default()));
+				$( <$relay_chain>::set_parachain_registered($para_id.into(), true); )*
+				if _routes_hrmp_through_relay() {
+					_open_relay_hrmp_channels();
+				}
+
+				$( <$parachain>::prepare_for_xcmp(); )*
+			}
+
+			/// Check the network is declared consistently with its chains: para ids and universal locations
+			/// of parachains, the host configuration and universal location of the relay chain, and HRMP
+			/// channels.
+			pub fn validate() -> Result<(), $crate::ValidationReport> {
+				let mut misconfigurations = <$relay_chain>::misconfigurations();
+				let relay_network = <$relay_chain>::global_consensus();
+				$( misconfigurations.extend(<$parachain>::misconfigurations($para_id, relay_network)); )*
+
+				let mut para_ids = $crate::BTreeSet::new();
+				for para_id in _para_ids() {
+					if !para_ids.insert(para_id) {
+						misconfigurations.push($crate::Misconfiguration::DuplicateParaId(para_id));
+					}
+				}
+
+				#[allow(unused_mut)]
+				let mut channels: Vec<(u32, u32)> = Vec::new();
+				$( $( channels.push(($sender, $recipient)); )* )?
+				for (sender, recipient) in channels {
+					if sender == recipient || !para_ids.contains(&sender) || !para_ids.contains(&recipient) {
+						misconfigurations.push($crate::Misconfiguration::InvalidHrmpChannel { sender, recipient });
+					}
+				}
+
+				if misconfigurations.is_empty() {
+					Ok(())
+				} else {
+					Err($crate::ValidationReport { network: stringify!($name), misconfigurations })
+				}
+			}
+
+			/// Onboard the declared parachain `para_id` offboarded with `deregister_parachain`. The
+			/// parachain starts over from its genesis.
+			pub fn register_parachain(para_id: u32) {
+				use $crate::TestExt;
+
+				assert!(_para_ids().contains(&para_id), "parachain {} is not declared in the network", para_id);
+				if _is_registered(para_id) {
+					return;
+				}
+
+				_DEREGISTERED_PARACHAINS.with(|b| b.borrow_mut().remove(&para_id));
+				<$relay_chain>::set_parachain_registered(para_id.into(), true);
+				if _routes_hrmp_through_relay() {
+					_open_relay_hrmp_channels();
+				}
+				match para_id {
+					$(
+						$para_id => {
+							<$parachain>::reset_ext();
+							<$parachain>::prepare_for_xcmp();
+						},
+					)*
+					_ => unreachable!(),
+				}
+			}
+
+			/// Offboard the parachain `para_id`. Its HRMP channels are closed, messages on the way from or
+			/// to it are dropped, and messages it sends or is sent are dropped until it's registered again.
+			/// Dropped messages are recorded as such in the journal, and those sent after the parachain is
+			/// offboarded fail the test in strict mode.
+			pub fn deregister_parachain(para_id: u32) {
+				assert!(_para_ids().contains(&para_id), "parachain {} is not declared in the network", para_id);
+
+				_DEREGISTERED_PARACHAINS.with(|b| b.borrow_mut().insert(para_id));
+				<$relay_chain>::set_parachain_registered(para_id.into(), false);
+
+				_record_parachain_drops(para_id);
+				_purge_parachain_messages(para_id);
+				_ON_DEMAND_ORDERS.with(|b| b.borrow_mut().remove(&para_id));
+			}
+
+			/// Rebuild the relay chain from genesis, keeping the parachains registered. Messages on the way
+			/// from or to the relay chain are dropped.
+			pub fn reset_relay_chain() {
+				use $crate::TestExt;
+
+				<$relay_chain>::reset_ext();
+				for para_id in _registered_para_ids() {
+					<$relay_chain>::set_parachain_registered(para_id.into(), true);
+				}
+				if _routes_hrmp_through_relay() {
+					_open_relay_hrmp_channels();
+				}
+			}
+
+			/// Rebuild the parachain `para_id` from genesis. Messages on the way from or to the parachain
+			/// are dropped.
+			pub fn reset_parachain(para_id: u32) {
+				use $crate::TestExt;
+
+				assert!(_para_ids().contains(&para_id), "parachain {} is not declared in the network", para_id);
+
+				match para_id {
+					$(
+						$para_id => <$parachain>::reset_ext(),
+					)*
+					_ => unreachable!(),
+				}
+				if !_is_registered(para_id) {
+					return;
+				}
+				if _routes_hrmp_through_relay() {
+					_open_relay_hrmp_channels();
+				}
+				match para_id {
+					$(
+						$para_id => <$parachain>::prepare_for_xcmp(),
+					)*
+					_ => unreachable!(),
+				}
+			}
+
+			/// Save the state of every chain and the message queues of the network as the checkpoint `name`,
+			/// replacing any checkpoint of the same name. The queues include the messages bridged to or from
+			/// the network, but the chains of bridged networks are left to their own checkpoints.
+			pub fn save_checkpoint(name: &str) {
+				let checkpoint = _Checkpoint {
+					relay_chain: <$relay_chain>::storage_snapshot(),
+					parachains: vec![$( <$parachain>::storage_snapshot(), )*],
+					downward_messages: _DOWNWARD_MESSAGES.with(|b| b.borrow().clone()),
+					dmp_processed: _DMP_PROCESSED.with(|b| b.borrow().clone()),
+					dmq_mqc_heads: _DMQ_MQC_HEADS.with(|b| b.borrow().clone()),
+					dmq_forwarded: _DMQ_FORWARDED.with(|b| b.borrow().clone()),
+					horizontal_messages: _HORIZONTAL_MESSAGES.with(|b| b.borrow().clone()),
+					upward_messages: _UPWARD_MESSAGES.with(|b| b.borrow().clone()),
+					hrmp_channel_usage: _HRMP_CHANNEL_USAGE.with(|b| b.borrow().clone()),
+					hrmp_delivered: _HRMP_DELIVERED.with(|b| b.borrow().clone()),
+					ump_weight_used: _UMP_WEIGHT_USED.with(|b| *b.borrow()),
+					message_delivery: _MESSAGE_DELIVERY.with(|b| *b.borrow()),
+					strict_mode: _STRICT_MODE.with(|b| *b.borrow()),
+					async_backing: _ASYNC_BACKING.with(|b| *b.borrow()),
+					relay_parent_lag: _RELAY_PARENT_LAG.with(|b| b.borrow().clone()),
+					relay_parent_blocks: _RELAY_PARENT_BLOCKS.with(|b| b.borrow().clone()),
+					hrmp_routing: _HRMP_ROUTING.with(|b| *b.borrow()),
+					inherent_messages: _INHERENT_MESSAGES.with(|b| b.borrow().clone()),
+					deregistered_parachains: _DEREGISTERED_PARACHAINS.with(|b| b.borrow().clone()),
+					on_demand_parachains: _ON_DEMAND_PARACHAINS.with(|b| b.borrow().clone()),
+					on_demand_orders: _ON_DEMAND_ORDERS.with(|b| b.borrow().clone()),
+					journal: _JOURNAL.with(|b| b.borrow().clone()),
+					sent_by: _SENT_BY.with(|b| b.borrow().clone()),
+					rejected_code_upgrades: _REJECTED_CODE_UPGRADES.with(|b| b.borrow().clone()),
+					bridged_messages: <$relay_chain>::global_consensus()
+						.map($crate::bridged_messages_of)
+						.unwrap_or_default(),
+				};
+				_CHECKPOINTS.with(|b| b.borrow_mut().insert(name.into(), checkpoint));
+			}
+
+			/// Return every chain and the message queues of the network to the checkpoint `name`. The
+			/// checkpoint is kept, so the network can return to it again.
+			pub fn restore_checkpoint(name: &str) {
+				let checkpoint = _CHECKPOINTS
+					.with(|b| b.borrow().get(name).cloned())
+					.unwrap_or_else(|| panic!("no checkpoint {} in the network", name));
+
+				<$relay_chain>::restore_storage(&checkpoint.relay_chain);
+				let mut parachains = checkpoint.parachains.iter();
+				$( <$parachain>::restore_storage(parachains.next().expect("a snapshot per parachain; qed")); )*
+
+				_DOWNWARD_MESSAGES.with(|b| b.replace(checkpoint.downward_messages));
+				_DMP_PROCESSED.with(|b| b.replace(checkpoint.dmp_processed));
+				_DMQ_MQC_HEADS.with(|b| b.replace(checkpoint.dmq_mqc_heads));
+				_DMQ_FORWARDED.with(|b| b.replace(checkpoint.dmq_forwarded));
+				_HORIZONTAL_MESSAGES.with(|b| b.replace(checkpoint.horizontal_messages));
+				_UPWARD_MESSAGES.with(|b| b.replace(checkpoint.upward_messages));
+				_HRMP_CHANNEL_USAGE.with(|b| b.replace(checkpoint.hrmp_channel_usage));
+				_HRMP_DELIVERED.with(|b| b.replace(checkpoint.hrmp_delivered));
+				_UMP_WEIGHT_USED.with(|b| b.replace(checkpoint.ump_weight_used));
+				_MESSAGE_DELIVERY.with(|b| b.replace(checkpoint.message_delivery));
+				_STRICT_MODE.with(|b| b.replace(checkpoint.strict_mode));
+				_ASYNC_BACKING.with(|b| b.replace(checkpoint.async_backing));
+				_RELAY_PARENT_LAG.with(|b| b.replace(checkpoint.relay_parent_lag));
+				_RELAY_PARENT_BLOCKS.with(|b| b.replace(checkpoint.relay_parent_blocks));
+				_HRMP_ROUTING.with(|b| b.replace(checkpoint.hrmp_routing));
+				_INHERENT_MESSAGES.with(|b| b.replace(checkpoint.inherent_messages));
+				_DEREGISTERED_PARACHAINS.with(|b| b.replace(checkpoint.deregistered_parachains));
+				_ON_DEMAND_PARACHAINS.with(|b| b.replace(checkpoint.on_demand_parachains));
+				_ON_DEMAND_ORDERS.with(|b| b.replace(checkpoint.on_demand_orders));
+				_JOURNAL.with(|b| b.replace(checkpoint.journal));
+				_SENT_BY.with(|b| b.replace(checkpoint.sent_by));
+				_REJECTED_CODE_UPGRADES.with(|b| b.replace(checkpoint.rejected_code_upgrades));
+				if let Some(network) = <$relay_chain>::global_consensus() {
+					$crate::restore_bridged_messages(network, checkpoint.bridged_messages);
+				}
+				_PROCESSING_MESSAGES.with(|b| b.replace(false));
+			}
+
+			/// Para ids of the parachains registered on the relay chain.
+			pub fn registered_parachains() -> Vec<u32> {
+				_registered_para_ids()
+			}
+
+			/// Set how downward and horizontal messages are delivered to parachains. Messages already
+			/// waiting for an inherent are still delivered by it.
+			pub fn set_message_delivery(delivery: $crate::MessageDelivery) {
+				_MESSAGE_DELIVERY.with(|b| b.replace(delivery));
+			}
+
+			/// Set if a message executed with an error fails the test, with the message and the error.
+			/// Messages are executed with errors silently by default, as in live networks.
+			pub fn set_strict_mode(strict: bool) {
+				_STRICT_MODE.with(|b| b.replace(strict));
+			}
+
+			/// Set how horizontal messages are routed. With `HrmpRouting::Relay`, the HRMP channels of the
+			/// network are opened in the relay chain `hrmp` pallet, messages are committed into it, and
+			/// recipients read them in their next block, whatever the message delivery. Switching back to
+			/// `HrmpRouting::Direct` hands the messages committed into the relay chain to their recipients.
+			pub fn set_hrmp_routing(routing: $crate::HrmpRouting) {
+				let routed_through_relay = _routes_hrmp_through_relay();
+				_HRMP_ROUTING.with(|b| b.replace(routing));
+				if _routes_hrmp_through_relay() {
+					_open_relay_hrmp_channels();
+				} else if routed_through_relay {
+					_drain_relay_hrmp_channels();
+					_process_messages();
+				}
+			}
+
+			/// Enable async backing with `params`, or disable it with `None`.
+			///
+			/// Under async backing, parachains build up to `max_blocks_per_relay_parent` blocks on the same
+			/// relay parent, which lags behind the relay chain head as set with `set_relay_parent_lag`.
+			/// Downward and horizontal messages are delivered in the first block of the recipient with a
+			/// relay parent not older than the relay chain block they were sent in.
+			pub fn set_async_backing(params: Option<$crate::AsyncBackingParams>) {
+				_ASYNC_BACKING.with(|b| b.replace(params));
+			}
+
+			/// Build the next blocks of the parachain `para_id` on the relay chain block `lag` blocks behind
+			/// the relay chain head, at most the async backing lookahead. Relay parents never go back.
+			pub fn set_relay_parent_lag(para_id: u32, lag: u32) {
+				_RELAY_PARENT_LAG.with(|b| b.borrow_mut().insert(para_id, lag));
+			}
+
+			/// Mark the parachain `para_id` as an on-demand parachain (parathread) or a parachain again.
+			///
+			/// An on-demand parachain doesn't produce a block on `execute_with`, only in `produce_blocks`
+			/// rounds it has an order for, or on an explicit `produce_block`. Messages to it wait in its
+			/// next inherent, and messages it sends leave with its next block.
+			pub fn set_on_demand(para_id: u32, on_demand: bool) {
+				assert!(_para_ids().contains(&para_id), "parachain {} is not declared in the network", para_id);
+
+				_ON_DEMAND_PARACHAINS.with(|b| {
+					if on_demand {
+						b.borrow_mut().insert(para_id);
+					} else {
+						b.borrow_mut().remove(&para_id);
+					}
+				});
+				if !on_demand {
+					_ON_DEMAND_ORDERS.with(|b| b.borrow_mut().remove(&para_id));
+				}
+			}
+
+			/// Order a block for the on-demand parachain `para_id`, produced in the next `produce_blocks`
+			/// round. Each order is good for one block.
+			pub fn place_order(para_id: u32) {
+				assert!(_is_on_demand(para_id), "parachain {} is not on-demand", para_id);
+
+				_ON_DEMAND_ORDERS.with(|b| *b.borrow_mut().entry(para_id).or_default() += 1);
+			}
+
+			/// Blocks ordered for the on-demand parachain `para_id` and not produced yet.
+			pub fn pending_orders(para_id: u32) -> u32 {
+				_ON_DEMAND_ORDERS.with(|b| b.borrow().get(&para_id).copied().unwrap_or_default())
+			}
+
+			/// Produce `n` blocks on the relay chain and every parachain, on-demand parachains only with an
+			/// order. Parachain blocks of the same round are built on the new relay chain block, and
+			/// messages are delivered after each round.
+			pub fn produce_blocks(n: u32) {
+				for _ in 0..n {
+					<$relay_chain>::produce_block();
+					$(
+						if _is_scheduled($para_id) {
+							<$parachain>::build_block();
+						}
+					)*
+
+					_process_messages();
+				}
+			}
+
+			/// Number of downward messages `para_id` has processed since the last reset.
+			pub fn processed_downward_messages(para_id: u32) -> u32 {
+				_DMP_PROCESSED.with(|b| b.borrow().get(&para_id).copied().unwrap_or_default())
+			}
+
+			/// Messages sent between chains of the network since the last reset matching `filter`, in the
+			/// order they were sent, with the outcome of their execution.
+			pub fn messages(filter: $crate::MessageFilter) -> Vec<$crate::JournalEntry> {
+				_JOURNAL.with(|b| b.borrow().iter().filter(|entry| filter.matches(entry)).cloned().collect())
+			}
+
+			/// Messages sent since the last reset, each under the message whose execution sent it. Print it
+			/// to see the path of messages through the network.
+			pub fn trace() -> $crate::MessageTrace {
+				_JOURNAL.with(|b| $crate::MessageTrace::new(&b.borrow(), _chain_name))
+			}
+
+			/// Upward messages not executed yet due to the relay chain weight limit, each message is:
+			/// `(from_para_id, msg)`.
+			pub fn deferred_upward_messages() -> Vec<(u32, Vec<u8>)> {
+				_UPWARD_MESSAGES.with(|b| b.borrow().iter().cloned().collect())
+			}
+
+			/// Overweight messages of the relay chain and every parachain.
+			pub fn overweight_messages() -> Vec<$crate::OverweightMessage> {
+				#[allow(unused_mut)]
+				let mut messages = <$relay_chain>::overweight_upward_messages();
+				$( messages.extend(<$parachain>::overweight_messages()); )*
+				messages
+			}
+
+			/// Execute the overweight message `index` of `queue` with at most `weight_limit`, as root.
+			pub fn service_overweight(
+				queue: $crate::OverweightQueue,
+				index: u64,
+				weight_limit: $crate::Weight,
+			) -> $crate::frame_support::dispatch::DispatchResultWithPostInfo {
+				match queue {
+					$crate::OverweightQueue::Upward => <$relay_chain>::service_overweight_upward_message(index, weight_limit),
+					$crate::OverweightQueue::Downward(para_id) => match para_id {
+						$( $para_id => <$parachain>::service_overweight_downward_message(index, weight_limit), )*
+						_ => panic!("unknown parachain {}", para_id),
+					},
+					$crate::OverweightQueue::Horizontal(para_id) => match para_id {
+						$( $para_id => <$parachain>::service_overweight_horizontal_message(index, weight_limit), )*
+						_ => panic!("unknown parachain {}", para_id),
+					},
+				}
+			}
+
+			/// Occupancy of the HRMP channel from `sender` to `recipient`: `(msg_count, total_size)`, as
+			/// in the relay chain `hrmp` pallet with `HrmpRouting::Relay`.
+			pub fn hrmp_channel_usage(sender: u32, recipient: u32) -> (u32, u32) {
+				if _routes_hrmp_through_relay() {
+					let id = $crate::cumulus_primitives_core::relay_chain::HrmpChannelId {
+						sender: sender.into(),
+						recipient: recipient.into(),
+					};
+					let (_, _, channels) = <$relay_chain>::hrmp_channels(sender.into());
+					return channels.get(&id).map_or((0, 0), |channel| (channel.msg_count, channel.total_size));
+				}
+				_HRMP_CHANNEL_USAGE.with(|b| b.borrow().get(&(sender, recipient)).copied().unwrap_or_default())
+			}
+
+			/// Parachain code upgrades rejected by the relay chain since the last reset, in the order the
+			/// code was sent.
+			pub fn rejected_code_upgrades() -> Vec<$crate::RejectedCodeUpgrade> {
+				_REJECTED_CODE_UPGRADES.with(|b| b.borrow().clone())
+			}
 
-						// send upward messages
-						let para_id = $crate::parachain_info::Pallet::<$runtime>::get();
-						for msg in collation_info.upward_messages.clone() {
-							_Messenger::send_upward_message(para_id.into(), msg);
-						}
+			/// Messages relayed to the network by bridges since the last reset, with the result of its bridge
+			/// hub sending them on. A message the bridge hub fails to send is dropped.
+			pub fn relayed_bridge_messages() -> Vec<$crate::RelayedBridgeMessage> {
+				<$relay_chain>::global_consensus().map_or_else(Vec::new, |network| {
+					$crate::RELAYED_BRIDGE_MESSAGES.with(|b| b.borrow().get(&network).cloned().unwrap_or_default())
+				})
+			}
+		}
 
-						// send horizontal messages
-						for msg in collation_info.horizontal_messages {
-							$crate::GLOBAL_RELAY.with(|v| {
-								let relay_block = *v.borrow();
-								_Messenger::send_horizontal_messages(
-									msg.recipient.into(),
-									vec![(para_id.into(), relay_block, msg.data)].into_iter(),
-								);
-							});
-						}
+		/// Hand the new validation code `code` of `para_id`, sent with its block built on
+		/// `relay_parent_number`, to the relay chain to be scheduled. A rejected upgrade is recorded for
+		/// `rejected_code_upgrades`.
+		fn _schedule_code_upgrade(para_id: u32, code: Vec<u8>, relay_parent_number: $crate::RelayBlockNumber) {
+			if !_is_registered(para_id) {
+				return;
+			}
+			if let Err(error) = <$relay_chain>::schedule_code_upgrade(para_id.into(), code, relay_parent_number) {
+				_REJECTED_CODE_UPGRADES.with(|b| {
+					b.borrow_mut().push($crate::RejectedCodeUpgrade { para_id, relay_parent_number, error })
+				});
+			}
+		}
 
-						// clean messages
-						ParachainSystem::on_initialize(block_number);
+		/// Relay parent number for a parachain block built after one on `last_relay_parent`. Parachain
+		/// blocks are built on the latest relay chain block, and the relay chain advances if it's not
+		/// newer than `last_relay_parent`.
+		fn _next_relay_parent_number(
+			para_id: u32,
+			last_relay_parent: $crate::RelayBlockNumber,
+		) -> $crate::RelayBlockNumber {
+			let relay_block = <$relay_chain>::block_number();
+			if let Some(params) = _ASYNC_BACKING.with(|b| *b.borrow()) {
+				let lag = _RELAY_PARENT_LAG.with(|b| b.borrow().get(&para_id).copied().unwrap_or_default());
+				let blocks_on = |relay_parent| {
+					_RELAY_PARENT_BLOCKS.with(|b| match b.borrow().get(&para_id) {
+						Some((p, blocks)) if *p == relay_parent => *blocks,
+						_ => 0,
 					})
-				});
+				};
 
-				_process_messages();
+				let mut relay_parent = relay_block.saturating_sub(lag.min(params.lookahead)).max(last_relay_parent);
+				while blocks_on(relay_parent) >= params.max_blocks_per_relay_parent.max(1) {
+					relay_parent += 1;
+				}
+				if relay_parent > relay_block {
+					<$relay_chain>::set_block_number(relay_parent);
+				}
 
-				r
+				let blocks = blocks_on(relay_parent) + 1;
+				_RELAY_PARENT_BLOCKS.with(|b| b.borrow_mut().insert(para_id, (relay_parent, blocks)));
+				return relay_parent;
 			}
-		}
-	};
-}
 
-thread_local! {
-	/// Downward messages, each message is: `(to_para_id, [(relay_block_number, msg)])`
-	#[allow(clippy::type_complexity)]
-	pub static DOWNWARD_MESSAGES: RefCell<VecDeque<(u32, Vec<(RelayBlockNumber, Vec<u8>)>)>>
-		= RefCell::new(VecDeque::new());
-		#[allow(clippy::type_complexity)]
-	/// Downward messages that already processed by parachains, each message is: `(to_para_id, relay_block_number, Vec<u8>)`
-	pub static DMP_DONE: RefCell<VecDeque<(u32, RelayBlockNumber, Vec<u8>)>>
-		= RefCell::new(VecDeque::new());
-	/// Horizontal messages, each message is: `(to_para_id, [(from_para_id, relay_block_number, msg)])`
-	#[allow(clippy::type_complexity)]
-	pub static HORIZONTAL_MESSAGES: RefCell<VecDeque<(u32, Vec<(ParaId, RelayBlockNumber, Vec<u8>)>)>>
-		= RefCell::new(VecDeque::new());
-	/// Upward messages, each message is: `(from_para_id, msg)
-	pub static UPWARD_MESSAGES: RefCell<VecDeque<(u32, Vec<u8>)>> = RefCell::new(VecDeque::new());
-	/// Global incremental relay chain block number
-	pub static GLOBAL_RELAY: RefCell<u32> = RefCell::new(1);
-}
+			if relay_block > last_relay_parent {
+				relay_block
+			} else {
+				<$relay_chain>::set_block_number(last_relay_parent + 1);
+				last_relay_parent + 1
+			}
+		}
 
-#[macro_export]
-macro_rules! decl_test_network {
-	(
-		pub struct $name:ident {
-			relay_chain = $relay_chain:ty,
-			parachains = vec![ $( ($para_id:expr, $parachain:ty), )* ],
+		fn _para_ids() -> Vec<u32> {
+			vec![$( $para_id, )*]
 		}
-	) => {
-		pub struct $name;
 
-		impl $name {
-			pub fn reset() {
-				use $crate::{TestExt, VecDeque};
+		fn _registered_para_ids() -> Vec<u32> {
+			_para_ids().into_iter().filter(|p| _is_registered(*p)).collect()
+		}
 
-				<$relay_chain>::reset_ext();
-				$( <$parachain>::reset_ext(); )*
+		fn _is_registered(para_id: u32) -> bool {
+			_para_ids().contains(&para_id) && !_DEREGISTERED_PARACHAINS.with(|b| b.borrow().contains(&para_id))
+		}
 
-				$( <$parachain>::prepare_for_xcmp(); )*
+		fn _is_on_demand(para_id: u32) -> bool {
+			_ON_DEMAND_PARACHAINS.with(|b| b.borrow().contains(&para_id))
+		}
 
-				$crate::DOWNWARD_MESSAGES.with(|b| b.replace(VecDeque::new()));
-				$crate::DMP_DONE.with(|b| b.replace(VecDeque::new()));
+		/// If `para_id` produces a block in the current round, which takes an order of an on-demand
+		/// parachain.
+		fn _is_scheduled(para_id: u32) -> bool {
+			if !_is_on_demand(para_id) {
+				return true;
 			}
+			_ON_DEMAND_ORDERS.with(|b| match b.borrow_mut().get_mut(&para_id) {
+				Some(count) if *count > 0 => {
+					*count -= 1;
+					true
+				},
+				_ => false,
+			})
 		}
 
-		fn _para_ids() -> Vec<u32> {
-			vec![$( $para_id, )*]
+		/// Open HRMP channels, each channel is: `(sender, recipient, config)`.
+		///
+		/// Channels declared without config use the limits of the relay chain host configuration. If
+		/// `hrmp_channels` is not declared, every parachain has a channel with default config to every
+		/// other parachain. Channels of parachains not registered are closed.
+		fn _hrmp_channels() -> Vec<(u32, u32, $crate::HrmpChannelConfig)> {
+			#[allow(unused_mut)]
+			let mut channels: Option<Vec<(u32, u32, $crate::HrmpChannelConfig)>> = None;
+			$(
+				channels = Some(vec![
+					$(
+						(
+							$sender,
+							$recipient,
+							{
+								#[allow(unused_mut)]
+								let mut config = None;
+								$( config = Some($channel_config); )?
+								config.unwrap_or_else(|| {
+									$crate::HrmpChannelConfig::from_host_configuration(&<$relay_chain>::host_configuration())
+								})
+							},
+						),
+					)*
+				]);
+			)?
+
+			channels.unwrap_or_else(|| {
+				let para_ids = _para_ids();
+				para_ids
+					.iter()
+					.flat_map(|s| {
+						para_ids
+							.iter()
+							.filter(move |r| *r != s)
+							.map(move |r| (*s, *r, Default::default()))
+					})
+					.collect()
+			})
+			.into_iter()
+			.filter(|(s, r, _)| _is_registered(*s) && _is_registered(*r))
+			.collect()
+		}
+
+		fn _hrmp_channel(sender: u32, recipient: u32) -> Option<$crate::HrmpChannelConfig> {
+			_hrmp_channels()
+				.into_iter()
+				.find(|(s, r, _)| *s == sender && *r == recipient)
+				.map(|(_, _, config)| config)
 		}
 
 		fn _process_messages() {
+			// messages sent during processing are handled by the outermost call
+			if _PROCESSING_MESSAGES.with(|b| b.replace(true)) {
+				return;
+			}
+
 			while _has_unprocessed_messages() {
-				_process_upward_messages();
-				_process_horizontal_messages();
-				_process_downward_messages();
+				let processed_upward = _process_upward_messages();
+				let processed_horizontal = _process_horizontal_messages();
+				let processed_downward = _process_downward_messages();
+				let relayed = <$crate::__or_default!([$($bridge)?] ()) as $crate::Bridge>::relay_messages();
+
+				// the rest are deferred to later blocks due to weight limits
+				if !(processed_upward || processed_horizontal || processed_downward || relayed) {
+					break;
+				}
 			}
+
+			_PROCESSING_MESSAGES.with(|b| b.replace(false));
 		}
 
 		fn _has_unprocessed_messages() -> bool {
-			$crate::DOWNWARD_MESSAGES.with(|b| !b.borrow_mut().is_empty())
-			|| $crate::HORIZONTAL_MESSAGES.with(|b| !b.borrow_mut().is_empty())
-			|| $crate::UPWARD_MESSAGES.with(|b| !b.borrow_mut().is_empty())
+			_DOWNWARD_MESSAGES.with(|b| !b.borrow().is_empty())
+			|| _HORIZONTAL_MESSAGES.with(|b| !b.borrow().is_empty())
+			|| _UPWARD_MESSAGES.with(|b| !b.borrow().is_empty())
+			|| $crate::BRIDGED_MESSAGES.with(|b| b.borrow().values().any(|messages| !messages.is_empty()))
 		}
 
-		fn _process_downward_messages() {
-			use $crate::{DmpMessageHandler, Bounded};
-			use polkadot_parachain::primitives::RelayChainBlockNumber;
+		fn _process_downward_messages() -> bool {
+			use $crate::DmpMessageHandler;
 
+			let mut processed = false;
 			while let Some((to_para_id, messages))
-				= $crate::DOWNWARD_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
+				= _DOWNWARD_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
+				processed = true;
+				if !_is_registered(to_para_id) {
+					for (_, msg) in &messages {
+						_record_drop(
+							$crate::MessageKind::Downward,
+							$crate::ChainId::Relay,
+							$crate::ChainId::Parachain(to_para_id),
+							$crate::blake2_256(msg),
+							to_para_id,
+						);
+					}
+					continue;
+				}
+				if _delivers_in_inherent(to_para_id) {
+					_INHERENT_MESSAGES.with(|b| {
+						b.borrow_mut().entry(to_para_id).or_default().0.extend(
+							messages.into_iter().map(|(sent_at, msg)| $crate::InboundDownwardMessage { sent_at, msg }),
+						)
+					});
+					continue;
+				}
+
+				let count = messages.len() as u32;
 				match to_para_id {
 					$(
 						$para_id => {
-							let mut msg_dedup: Vec<(RelayChainBlockNumber, Vec<u8>)> = Vec::new();
-							for m in messages {
-								msg_dedup.push((m.0, m.1.clone()));
-							}
-							msg_dedup.dedup();
-
-							let msgs = msg_dedup.clone().into_iter().filter(|m| {
-								!$crate::DMP_DONE.with(|b| b.borrow_mut().contains(&(to_para_id, m.0, m.1.clone())))
-							}).collect::<Vec<(RelayChainBlockNumber, Vec<u8>)>>();
-							if msgs.len() != 0 {
-								for m in msgs.clone() {
-									$crate::DMP_DONE.with(|b| b.borrow_mut().push_back((to_para_id, m.0, m.1)));
-								}
-								<$parachain>::handle_dmp_messages(msgs.into_iter(), $crate::Weight::max_value());
-							}
+							<$parachain>::handle_dmp_messages(messages.into_iter(), <$parachain>::reserved_dmp_weight());
 						},
 					)*
 					_ => unreachable!(),
 				}
+
+				_DMP_PROCESSED.with(|b| *b.borrow_mut().entry(to_para_id).or_default() += count);
+				// the block of the recipient processed the messages
+				_prune_dmq(to_para_id, count);
 			}
+			processed
 		}
 
-		fn _process_horizontal_messages() {
-			use $crate::{XcmpMessageHandler, Bounded};
+		fn _process_horizontal_messages() -> bool {
+			use $crate::XcmpMessageHandler;
 
+			let mut processed = false;
 			while let Some((to_para_id, messages))
-				= $crate::HORIZONTAL_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
+				= _HORIZONTAL_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
+				processed = true;
+				if !_is_registered(to_para_id) {
+					for (from_para_id, _, page) in &messages {
+						for xcm in _page_xcms(page) {
+							_record_drop(
+								$crate::MessageKind::Horizontal,
+								$crate::ChainId::Parachain((*from_para_id).into()),
+								$crate::ChainId::Parachain(to_para_id),
+								$crate::blake2_256(&xcm),
+								to_para_id,
+							);
+						}
+					}
+					continue;
+				}
+				if _delivers_in_inherent(to_para_id) {
+					_INHERENT_MESSAGES.with(|b| {
+						let mut b = b.borrow_mut();
+						let horizontal_messages = &mut b.entry(to_para_id).or_default().1;
+						for (from_para_id, sent_at, data) in messages {
+							horizontal_messages
+								.entry(from_para_id)
+								.or_default()
+								.push($crate::InboundHrmpMessage { sent_at, data });
+						}
+					});
+					continue;
+				}
+
 				let iter = messages.iter().map(|(p, b, m)| (*p, *b, &m[..])).collect::<Vec<_>>().into_iter();
 				match to_para_id {
 					$(
 						$para_id => {
-							<$parachain>::handle_xcmp_messages(iter, $crate::Weight::max_value());
+							<$parachain>::handle_xcmp_messages(iter, <$parachain>::reserved_xcmp_weight());
 						},
 					)*
 					_ => unreachable!(),
 				}
+
+				// the messages occupy the channel until the next block of the recipient
+				_HRMP_DELIVERED.with(|b| {
+					b.borrow_mut().entry(to_para_id).or_default().extend(
+						messages.iter().map(|(from_para_id, _, msg)| ((*from_para_id).into(), msg.len() as u32)),
+					)
+				});
+			}
+			processed
+		}
+
+		/// If messages to `para_id` wait for its next inherent, which is always the case for an on-demand
+		/// parachain and under async backing.
+		fn _delivers_in_inherent(para_id: u32) -> bool {
+			_MESSAGE_DELIVERY.with(|b| *b.borrow()) == $crate::MessageDelivery::Inherent
+				|| _is_on_demand(para_id)
+				|| _ASYNC_BACKING.with(|b| b.borrow().is_some())
+		}
+
+		/// Drop messages on the way from or to `para_id`.
+		fn _purge_parachain_messages(para_id: u32) {
+			_DOWNWARD_MESSAGES.with(|b| b.borrow_mut().retain(|(p, _)| *p != para_id));
+			_HORIZONTAL_MESSAGES.with(|b| {
+				let mut b = b.borrow_mut();
+				b.retain(|(p, _)| *p != para_id);
+				for (_, messages) in b.iter_mut() {
+					messages.retain(|(from_para_id, _, _)| u32::from(*from_para_id) != para_id);
+				}
+				b.retain(|(_, messages)| !messages.is_empty());
+			});
+			_INHERENT_MESSAGES.with(|b| {
+				let mut b = b.borrow_mut();
+				b.remove(&para_id);
+				for (_, (_, horizontal_messages)) in b.iter_mut() {
+					horizontal_messages.remove(&para_id.into());
+				}
+			});
+			_UPWARD_MESSAGES.with(|b| b.borrow_mut().retain(|(p, _)| *p != para_id));
+			_HRMP_CHANNEL_USAGE.with(|b| b.borrow_mut().retain(|(s, r), _| *s != para_id && *r != para_id));
+			_HRMP_DELIVERED.with(|b| {
+				let mut b = b.borrow_mut();
+				b.remove(&para_id);
+				for (_, delivered) in b.iter_mut() {
+					delivered.retain(|(from_para_id, _)| *from_para_id != para_id);
+				}
+			});
+			_DMP_PROCESSED.with(|b| b.borrow_mut().remove(&para_id));
+			_RELAY_PARENT_BLOCKS.with(|b| b.borrow_mut().remove(&para_id));
+			_restart_dmq_mqc(para_id);
+			if _routes_hrmp_through_relay() {
+				// closing the channels drops the horizontal messages in the relay chain storage
+				<$relay_chain>::close_hrmp_channels(para_id.into());
 			}
 		}
 
-		fn _process_upward_messages() {
-			use $crate::{UmpSink, Bounded};
-			while let Some((from_para_id, msg)) = $crate::UPWARD_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
-				let _ =  <$relay_chain>::process_upward_message(
-					from_para_id.into(),
-					&msg[..],
-					$crate::Weight::max_value(),
-				);
+		/// Drop messages on the way from or to the relay chain.
+		fn _purge_relay_chain_messages() {
+			_DOWNWARD_MESSAGES.with(|b| b.borrow_mut().clear());
+			_INHERENT_MESSAGES.with(|b| {
+				let mut b = b.borrow_mut();
+				for (_, (downward_messages, _)) in b.iter_mut() {
+					downward_messages.clear();
+				}
+				b.retain(|_, (_, horizontal_messages)| !horizontal_messages.is_empty());
+			});
+			_UPWARD_MESSAGES.with(|b| b.borrow_mut().clear());
+			_UMP_WEIGHT_USED.with(|b| b.replace((0, $crate::Weight::zero())));
+			for para_id in _para_ids() {
+				_restart_dmq_mqc(para_id);
+			}
+		}
+
+		/// Start the downward message queue chain of `para_id` over in the relay chain and the parachain,
+		/// once messages to it are dropped.
+		fn _restart_dmq_mqc(para_id: u32) {
+			_DMQ_MQC_HEADS.with(|b| b.borrow_mut().remove(&para_id));
+			_DMQ_FORWARDED.with(|b| b.borrow_mut().remove(&para_id));
+			<$relay_chain>::clean_dmq(para_id.into());
+			match para_id {
+				$(
+					$para_id => <$parachain>::restart_dmq_mqc(),
+				)*
+				_ => unreachable!(),
+			}
+		}
+
+		fn _routes_hrmp_through_relay() -> bool {
+			_HRMP_ROUTING.with(|b| *b.borrow()) == $crate::HrmpRouting::Relay
+		}
+
+		fn _open_relay_hrmp_channels() {
+			for (sender, recipient, config) in _hrmp_channels() {
+				<$relay_chain>::open_hrmp_channel(sender.into(), recipient.into(), config);
+			}
+		}
+
+		/// Take the horizontal messages committed into the relay chain, to be handed to their recipients
+		/// directly. They occupy their channel until received.
+		fn _drain_relay_hrmp_channels() {
+			let relay_block = <$relay_chain>::block_number();
+			for para_id in _registered_para_ids() {
+				let mut messages = Vec::new();
+				for (from_para_id, received) in <$relay_chain>::prune_hrmp(para_id.into(), relay_block) {
+					for msg in received {
+						_HRMP_CHANNEL_USAGE.with(|b| {
+							let mut usage = b.borrow_mut();
+							let (msg_count, total_size) = usage.entry((from_para_id.into(), para_id)).or_default();
+							*msg_count += 1;
+							*total_size += msg.data.len() as u32;
+						});
+						messages.push((from_para_id, msg.sent_at, msg.data));
+					}
+				}
+				if !messages.is_empty() {
+					_HORIZONTAL_MESSAGES.with(|b| b.borrow_mut().push_back((para_id, messages)));
+				}
+			}
+		}
+
+		fn _release_hrmp_channel(sender: u32, recipient: u32, msg_size: u32) {
+			_HRMP_CHANNEL_USAGE.with(|b| {
+				if let Some((msg_count, total_size)) = b.borrow_mut().get_mut(&(sender, recipient)) {
+					*msg_count = msg_count.saturating_sub(1);
+					*total_size = total_size.saturating_sub(msg_size);
+				}
+			});
+		}
+
+		/// Take messages waiting for the next inherent of `para_id`, which are received once taken. The
+		/// channels of horizontal messages received by the parachain are released.
+		#[allow(clippy::type_complexity)]
+		fn _take_inherent_messages(
+			para_id: u32,
+			relay_parent_number: $crate::RelayBlockNumber,
+		) -> (
+			Vec<$crate::InboundDownwardMessage>,
+			$crate::BTreeMap<$crate::ParaId, Vec<$crate::InboundHrmpMessage>>,
+		) {
+			// messages sent after the relay parent wait for a later block
+			let (downward_messages, mut horizontal_messages) = _INHERENT_MESSAGES.with(|b| {
+				let mut b = b.borrow_mut();
+				let (downward, horizontal) = match b.get_mut(&para_id) {
+					Some(messages) => messages,
+					None => return Default::default(),
+				};
+
+				let received = downward.iter().take_while(|msg| msg.sent_at <= relay_parent_number).count();
+				let downward_messages = downward.drain(..received).collect::<Vec<_>>();
+				let mut horizontal_messages = $crate::BTreeMap::new();
+				for (from_para_id, messages) in horizontal.iter_mut() {
+					let received = messages.iter().take_while(|msg| msg.sent_at <= relay_parent_number).count();
+					if received > 0 {
+						horizontal_messages.insert(*from_para_id, messages.drain(..received).collect::<Vec<_>>());
+					}
+				}
+				horizontal.retain(|_, messages| !messages.is_empty());
+
+				if downward.is_empty() && horizontal.is_empty() {
+					b.remove(&para_id);
+				}
+				(downward_messages, horizontal_messages)
+			});
+
+			_DMP_PROCESSED.with(|b| {
+				*b.borrow_mut().entry(para_id).or_default() += downward_messages.len() as u32
+			});
+			// messages handed to the parachain in its previous blocks are consumed
+			for (from_para_id, msg_size) in _HRMP_DELIVERED.with(|b| b.borrow_mut().remove(&para_id)).unwrap_or_default() {
+				_release_hrmp_channel(from_para_id, para_id, msg_size);
+			}
+			for (from_para_id, messages) in &horizontal_messages {
+				for msg in messages {
+					_release_hrmp_channel((*from_para_id).into(), para_id, msg.data.len() as u32);
+				}
+			}
+
+			if _routes_hrmp_through_relay() {
+				// the candidate advances the watermark of the parachain to its relay parent, releasing the
+				// channels in the relay chain
+				for (from_para_id, messages) in <$relay_chain>::prune_hrmp(para_id.into(), relay_parent_number) {
+					horizontal_messages.entry(from_para_id).or_default().extend(messages);
+				}
+			}
+
+			(downward_messages, horizontal_messages)
+		}
+
+		fn _process_upward_messages() -> bool {
+			use $crate::UmpSink;
+
+			let relay_block = <$relay_chain>::block_number();
+			let config = <$relay_chain>::host_configuration();
+			let total_weight = config.ump_service_total_weight;
+			let max_individual_weight = config.ump_max_individual_weight;
+
+			let mut processed = false;
+			while let Some((from_para_id, msg)) = _UPWARD_MESSAGES.with(|b| b.borrow_mut().pop_front()) {
+				let weight_used = _UMP_WEIGHT_USED.with(|v| {
+					let mut v = v.borrow_mut();
+					if v.0 != relay_block {
+						*v = (relay_block, $crate::Weight::zero());
+					}
+					v.1
+				});
+
+				// the first message of a block may use up to `ump_max_individual_weight`, as in the `ump`
+				// pallet, so a message heavier than `ump_service_total_weight` doesn't block the queue
+				let max_weight = if weight_used.is_zero() {
+					max_individual_weight
+				} else {
+					total_weight.saturating_sub(weight_used)
+				};
+				match <$relay_chain>::process_upward_message(from_para_id.into(), &msg[..], max_weight) {
+					Ok(weight) => {
+						_UMP_WEIGHT_USED.with(|v| v.borrow_mut().1 = weight_used.saturating_add(weight));
+						processed = true;
+					}
+					Err((id, required)) if required.any_gt(max_individual_weight) => {
+						// never fits in a relay block, keep it for manual servicing
+						<$relay_chain>::stash_overweight_upward_message(from_para_id.into(), msg, id, required);
+						_record_executions(
+							$crate::ChainId::Relay,
+							vec![$crate::ExecutedMessage {
+								kind: $crate::MessageKind::Upward,
+								id,
+								outcome: $crate::Outcome::Error($crate::XcmError::WeightLimitReached(required)),
+								sent: Vec::new(),
+							}],
+						);
+						processed = true;
+					}
+					Err(_) => {
+						// not enough weight left in this relay block, retry in the next one
+						_UPWARD_MESSAGES.with(|b| b.borrow_mut().push_front((from_para_id, msg)));
+						break;
+					}
+				}
 			}
+			processed
 		}
 
 		pub struct _Messenger;
 		impl _Messenger {
 			fn send_downward_messages(to_para_id: u32, iter: impl Iterator<Item = ($crate::RelayBlockNumber, Vec<u8>)>) {
-				$crate::DOWNWARD_MESSAGES.with(|b| b.borrow_mut().push_back((to_para_id, iter.collect())));
+				let messages = iter.collect::<Vec<_>>();
+				for (sent_at, msg) in &messages {
+					_record_message(
+						$crate::MessageKind::Downward,
+						$crate::ChainId::Relay,
+						$crate::ChainId::Parachain(to_para_id),
+						*sent_at,
+						msg.clone(),
+					);
+				}
+				_DOWNWARD_MESSAGES.with(|b| b.borrow_mut().push_back((to_para_id, messages)));
 			}
 
 			fn send_horizontal_messages<
 				I: Iterator<Item = ($crate::ParaId, $crate::RelayBlockNumber, Vec<u8>)>,
 			>(to_para_id: u32, iter: I) {
-				$crate::HORIZONTAL_MESSAGES.with(|b| b.borrow_mut().push_back((to_para_id, iter.collect())));
+				let messages = iter.collect::<Vec<_>>();
+				for (from_para_id, sent_at, msg) in &messages {
+					let from_para_id: u32 = (*from_para_id).into();
+					assert!(
+						_hrmp_channel(from_para_id, to_para_id).is_some(),
+						"no open HRMP channel from {} to {}",
+						from_para_id,
+						to_para_id,
+					);
+
+					// the sender keeps pages exceeding the limits of the channel, as advertised in its relay
+					// chain state proof, in its outbound queue; the relay chain tracks the occupancy of
+					// channels the messages are committed into
+					if !_routes_hrmp_through_relay() {
+						_HRMP_CHANNEL_USAGE.with(|b| {
+							let mut usage = b.borrow_mut();
+							let (msg_count, total_size) = usage.entry((from_para_id, to_para_id)).or_default();
+							*msg_count += 1;
+							*total_size += msg.len() as u32;
+						});
+					}
+
+					for xcm in _page_xcms(msg) {
+						_record_message(
+							$crate::MessageKind::Horizontal,
+							$crate::ChainId::Parachain(from_para_id),
+							$crate::ChainId::Parachain(to_para_id),
+							*sent_at,
+							xcm,
+						);
+					}
+				}
+
+				if _routes_hrmp_through_relay() {
+					let mut messages_by_sender = $crate::BTreeMap::<$crate::ParaId, Vec<Vec<u8>>>::new();
+					for (from_para_id, _, msg) in messages {
+						messages_by_sender.entry(from_para_id).or_default().push(msg);
+					}
+					for (from_para_id, messages) in messages_by_sender {
+						<$relay_chain>::queue_outbound_hrmp(from_para_id, to_para_id.into(), messages);
+					}
+					return;
+				}
+				_HORIZONTAL_MESSAGES.with(|b| b.borrow_mut().push_back((to_para_id, messages)));
+			}
+
+			fn send_upward_messages(from_para_id: u32, relay_block: $crate::RelayBlockNumber, msgs: Vec<Vec<u8>>) {
+				// candidates of parachains not registered are not included
+				if !_is_registered(from_para_id) {
+					for msg in msgs {
+						let id = $crate::blake2_256(&msg);
+						_record_message(
+							$crate::MessageKind::Upward,
+							$crate::ChainId::Parachain(from_para_id),
+							$crate::ChainId::Relay,
+							relay_block,
+							msg,
+						);
+						_record_drop(
+							$crate::MessageKind::Upward,
+							$crate::ChainId::Parachain(from_para_id),
+							$crate::ChainId::Relay,
+							id,
+							from_para_id,
+						);
+					}
+					return;
+				}
+
+				let config = <$relay_chain>::host_configuration();
+				assert!(
+					msgs.len() as u32 <= config.max_upward_message_num_per_candidate,
+					"too many upward messages from {} in one candidate",
+					from_para_id,
+				);
+
+				for msg in msgs {
+					let size = msg.len() as u32;
+					assert!(
+						size <= config.max_upward_message_size,
+						"upward message from {} exceeds max message size",
+						from_para_id,
+					);
+
+					let (queue_count, queue_size) = _upward_queue_size(from_para_id);
+					assert!(
+						queue_count < config.max_upward_queue_count && queue_size + size <= config.max_upward_queue_size,
+						"upward message queue of {} is full",
+						from_para_id,
+					);
+
+					_record_message(
+						$crate::MessageKind::Upward,
+						$crate::ChainId::Parachain(from_para_id),
+						$crate::ChainId::Relay,
+						relay_block,
+						msg.clone(),
+					);
+					_UPWARD_MESSAGES.with(|b| b.borrow_mut().push_back((from_para_id, msg)));
+				}
+			}
+		}
+
+		fn _record_message(
+			kind: $crate::MessageKind,
+			sender: $crate::ChainId,
+			recipient: $crate::ChainId,
+			sent_at: $crate::RelayBlockNumber,
+			data: Vec<u8>,
+		) {
+			let id = $crate::blake2_256(&data);
+			let cause = match sender {
+				$crate::ChainId::Relay => _RELAY_CAUSE.with(|b| *b.borrow()),
+				_ => _SENT_BY.with(|b| {
+					let mut b = b.borrow_mut();
+					let causes = b.get_mut(&(sender, id))?;
+					let cause = causes.remove(0);
+					if causes.is_empty() {
+						b.remove(&(sender, id));
+					}
+					Some(cause)
+				}),
+			};
+			_JOURNAL.with(|b| {
+				b.borrow_mut().push($crate::JournalEntry {
+					kind,
+					sender,
+					recipient,
+					sent_at,
+					id,
+					data,
+					outcome: None,
+					dropped: false,
+					cause,
+				})
+			});
+		}
+
+		/// Record the outcomes of messages executed by `recipient` in the journal, each for the first message
+		/// of the same kind and id not executed yet. Returns the journal indices of the messages.
+		fn _record_executions(recipient: $crate::ChainId, executions: Vec<$crate::ExecutedMessage>) -> Vec<usize> {
+			let mut indices = Vec::new();
+			for executed in executions {
+				let index = _JOURNAL.with(|b| {
+					let mut journal = b.borrow_mut();
+					let index = journal.iter().position(|e| {
+						e.recipient == recipient && e.kind == executed.kind && e.id == executed.id && e.status() == $crate::MessageStatus::Pending
+					})?;
+					journal[index].outcome = Some(executed.outcome);
+					Some(index)
+				});
+				let index = match index {
+					Some(index) => index,
+					None => continue,
+				};
+
+				if _STRICT_MODE.with(|b| *b.borrow()) {
+					let entry = _JOURNAL.with(|b| b.borrow()[index].clone());
+					if let Some(error) = entry.error() {
+						panic!(
+							"{:?} message from {} to {} failed with {:?}: {:?}",
+							entry.kind,
+							_chain_name(entry.sender),
+							_chain_name(entry.recipient),
+							error,
+							entry.xcm(),
+						);
+					}
+				}
+
+				// messages leave the chain with its next block
+				_SENT_BY.with(|b| {
+					let mut b = b.borrow_mut();
+					for id in executed.sent {
+						b.entry((recipient, id)).or_default().push(index);
+					}
+				});
+				indices.push(index);
+			}
+			indices
+		}
+
+		/// Record the first pending message of `kind` from `sender` to `recipient` with the id `id` as
+		/// dropped, as the parachain `para_id` isn't registered. Fails the test in strict mode.
+		fn _record_drop(
+			kind: $crate::MessageKind,
+			sender: $crate::ChainId,
+			recipient: $crate::ChainId,
+			id: $crate::XcmHash,
+			para_id: u32,
+		) {
+			let entry = _JOURNAL.with(|b| {
+				let mut journal = b.borrow_mut();
+				let entry = journal.iter_mut().find(|e| {
+					e.kind == kind
+						&& e.sender == sender
+						&& e.recipient == recipient
+						&& e.id == id
+						&& e.status() == $crate::MessageStatus::Pending
+				})?;
+				entry.dropped = true;
+				Some(entry.clone())
+			});
+
+			if let Some(entry) = entry {
+				if _STRICT_MODE.with(|b| *b.borrow()) {
+					panic!(
+						"{:?} message from {} to {} dropped, parachain {} is not registered: {:?}",
+						entry.kind,
+						_chain_name(entry.sender),
+						_chain_name(entry.recipient),
+						para_id,
+						entry.xcm(),
+					);
+				}
+			}
+		}
+
+		/// Record the messages on the way from or to the parachain `para_id` being offboarded as dropped.
+		fn _record_parachain_drops(para_id: u32) {
+			let parachain = $crate::ChainId::Parachain(para_id);
+			let mut ids = Vec::new();
+			_UPWARD_MESSAGES.with(|b| {
+				for (_, msg) in b.borrow().iter().filter(|(p, _)| *p == para_id) {
+					ids.push(($crate::MessageKind::Upward, parachain, $crate::ChainId::Relay, $crate::blake2_256(msg)));
+				}
+			});
+			let mut pages = Vec::new();
+			_HORIZONTAL_MESSAGES.with(|b| {
+				for (to_para_id, messages) in b.borrow().iter() {
+					for (from_para_id, _, page) in messages.iter().filter(|(p, _, _)| u32::from(*p) == para_id) {
+						pages.push((u32::from(*from_para_id), *to_para_id, page.clone()));
+					}
+				}
+			});
+			_INHERENT_MESSAGES.with(|b| {
+				for (to_para_id, (_, horizontal_messages)) in b.borrow().iter() {
+					for msg in horizontal_messages.get(&para_id.into()).into_iter().flatten() {
+						pages.push((para_id, *to_para_id, msg.data.clone()));
+					}
+				}
+			});
+			if _routes_hrmp_through_relay() {
+				let (egress, _, _) = <$relay_chain>::hrmp_channels(para_id.into());
+				for recipient in egress {
+					for msg in <$relay_chain>::hrmp_channel_contents(para_id.into(), recipient) {
+						pages.push((para_id, recipient.into(), msg.data));
+					}
+				}
+			}
+			for (from_para_id, to_para_id, page) in pages {
+				for xcm in _page_xcms(&page) {
+					ids.push((
+						$crate::MessageKind::Horizontal,
+						$crate::ChainId::Parachain(from_para_id),
+						$crate::ChainId::Parachain(to_para_id),
+						$crate::blake2_256(&xcm),
+					));
+				}
+			}
+
+			_JOURNAL.with(|b| {
+				let mut journal = b.borrow_mut();
+				for (kind, sender, recipient, id) in ids {
+					if let Some(entry) = journal.iter_mut().find(|e| {
+						e.kind == kind
+							&& e.sender == sender
+							&& e.recipient == recipient
+							&& e.id == id
+							&& e.status() == $crate::MessageStatus::Pending
+					}) {
+						entry.dropped = true;
+					}
+				}
+				// messages to the parachain are dropped with its queues
+				for entry in journal.iter_mut().filter(|e| e.recipient == parachain) {
+					if entry.status() == $crate::MessageStatus::Pending {
+						entry.dropped = true;
+					}
+				}
+			});
+		}
+
+		/// The XCMs of the horizontal message page `page`, as recorded in the journal. The XCMs decoded
+		/// before an item that can't be decoded are kept, and the rest of the page from that item, or the
+		/// whole page if its format can't be decoded, is one more message with undecodable data.
+		fn _page_xcms(page: &[u8]) -> Vec<Vec<u8>> {
+			let (decoded, rest) = match $crate::XcmpPage::decode_prefix(page) {
+				Ok(decoded) => decoded,
+				Err(_) => return vec![page.to_vec()],
+			};
+
+			let mut xcms = decoded
+				.items
+				.into_iter()
+				.filter_map(|item| match item {
+					$crate::XcmpPageItem::Xcm { encoded, .. } => Some(encoded),
+					_ => None,
+				})
+				.collect::<Vec<_>>();
+			if !rest.is_empty() && decoded.format == $crate::XcmpMessageFormat::ConcatenatedVersionedXcm {
+				xcms.push(rest.to_vec());
+			}
+			xcms
+		}
+
+		fn _chain_name(chain: $crate::ChainId) -> &'static str {
+			match chain {
+				$crate::ChainId::Relay => stringify!($relay_chain),
+				$(
+					$crate::ChainId::Parachain(para_id) if para_id == $para_id => stringify!($parachain),
+				)*
+				_ => "unknown",
 			}
+		}
+
+		/// Upward messages from `para_id` waiting in the relay chain: `(msg_count, total_size)`.
+		fn _upward_queue_size(para_id: u32) -> (u32, u32) {
+			_UPWARD_MESSAGES.with(|b| {
+				b.borrow()
+					.iter()
+					.filter(|(from_para_id, _)| *from_para_id == para_id)
+					.fold((0, 0), |(count, size), (_, msg)| (count + 1, size + msg.len() as u32))
+			})
+		}
+
+		/// Number of downward messages sent to `para_id` and still in the relay chain DMQ.
+		fn _dmq_forwarded(para_id: u32) -> u32 {
+			_DMQ_FORWARDED.with(|b| b.borrow().get(&para_id).copied().unwrap_or_default())
+		}
 
-			fn send_upward_message(from_para_id: u32, msg: Vec<u8>) {
-				$crate::UPWARD_MESSAGES.with(|b| b.borrow_mut().push_back((from_para_id, msg)));
+		/// Prune the `processed` downward messages of `para_id` from the relay chain DMQ once a block of
+		/// the parachain processed them, as the inclusion of its candidate does.
+		fn _prune_dmq(para_id: u32, processed: u32) {
+			if processed == 0 {
+				return;
 			}
+
+			_DMQ_FORWARDED.with(|b| {
+				let mut b = b.borrow_mut();
+				let forwarded = b.entry(para_id).or_default();
+				*forwarded = forwarded.saturating_sub(processed);
+				if *forwarded == 0 {
+					b.remove(&para_id);
+				}
+			});
+			<$relay_chain>::prune_dmq(para_id.into(), processed);
+		}
+
+		/// Record `head` as the MQC head of the relay chain DMQ of `para_id` with the messages sent up to
+		/// the relay chain block `sent_at`.
+		fn _note_dmq_mqc_head(para_id: u32, sent_at: $crate::RelayBlockNumber, head: $crate::RelayHash) {
+			_DMQ_MQC_HEADS.with(|b| b.borrow_mut().entry(para_id).or_default().insert(sent_at, head));
+		}
+
+		/// MQC head of the relay chain DMQ of `para_id` at the relay chain block `relay_parent_number`.
+		fn _dmq_mqc_head(para_id: u32, relay_parent_number: $crate::RelayBlockNumber) -> $crate::RelayHash {
+			_DMQ_MQC_HEADS.with(|b| {
+				b.borrow()
+					.get(&para_id)
+					.and_then(|heads| heads.range(..=relay_parent_number).next_back().map(|(_, head)| *head))
+					.unwrap_or_default()
+			})
 		}
 
-		fn _hrmp_channel_parachain_inherent_data(
+		/// Inherent data of the next block of `para_id`, carrying the messages waiting for it. The DMQ MQC
+		/// head in the relay chain state proof is the one of the relay chain at `relay_parent_number`, and
+		/// HRMP MQC heads extend the last ones of the parachain by the messages.
+		fn _parachain_inherent_data(
 			para_id: u32,
 			relay_parent_number: u32,
+			mut hrmp_mqc_heads: $crate::BTreeMap<$crate::ParaId, $crate::MessageQueueChain>,
 		) -> $crate::ParachainInherentData {
 			use $crate::cumulus_primitives_core::{relay_chain::HrmpChannelId, AbridgedHrmpChannel};
 
+			let (downward_messages, horizontal_messages) = _take_inherent_messages(para_id, relay_parent_number);
+			for (from_para_id, messages) in &horizontal_messages {
+				let head = hrmp_mqc_heads.entry(*from_para_id).or_default();
+				for msg in messages {
+					head.extend_hrmp(msg);
+				}
+			}
+
 			let mut sproof = $crate::RelayStateSproofBuilder::default();
 			sproof.para_id = para_id.into();
+			sproof.dmq_mqc_head = Some(_dmq_mqc_head(para_id, relay_parent_number));
+			// one relay chain block per slot
+			sproof.current_slot = (relay_parent_number as u64).into();
+			(sproof.upgrade_go_ahead, sproof.upgrade_restriction) = <$relay_chain>::upgrade_signals(para_id.into());
+			if sproof.upgrade_go_ahead.is_some() {
+				// the parachain acts on the signal in this block
+				<$relay_chain>::note_new_head(para_id.into());
+			}
+			sproof.host_config = $crate::abridged_host_configuration(&<$relay_chain>::host_configuration());
+			// upward messages exceeding the relay chain queue limits stay pending in the parachain
+			sproof.relay_dispatch_queue_size = Some(_upward_queue_size(para_id));
 
-			// egress channel
-			let e_index = sproof.hrmp_egress_channel_index.get_or_insert_with(Vec::new);
-			for recipient_para_id in &[ $( $para_id, )* ] {
-				let recipient_para_id = $crate::ParaId::from(*recipient_para_id);
-				if let Err(idx) = e_index.binary_search(&recipient_para_id) {
-					e_index.insert(idx, recipient_para_id);
+			if _routes_hrmp_through_relay() {
+				// the channels are read from the relay chain; its MQC heads also cover messages sent after
+				// the relay parent, so ingress channels carry the head of the messages received instead
+				let (egress, ingress, channels) = <$relay_chain>::hrmp_channels(sproof.para_id);
+				sproof.hrmp_egress_channel_index = Some(egress);
+				sproof.hrmp_ingress_channel_index = Some(ingress);
+				for (id, channel) in channels {
+					let mqc_head = if id.recipient == sproof.para_id {
+						hrmp_mqc_heads.get(&id.sender).map(|head| head.head())
+					} else {
+						Option::None
+					};
+					sproof.hrmp_channels.insert(
+						id,
+						AbridgedHrmpChannel {
+							max_capacity: channel.max_capacity,
+							max_total_size: channel.max_total_size,
+							max_message_size: channel.max_message_size,
+							msg_count: channel.msg_count,
+							total_size: channel.total_size,
+							mqc_head,
+						},
+					);
 				}
+			} else {
+				let e_index = sproof.hrmp_egress_channel_index.get_or_insert_with(Vec::new);
+				let i_index = sproof.hrmp_ingress_channel_index.get_or_insert_with(Vec::new);
+				for (sender, recipient, config) in _hrmp_channels() {
+					let sender = $crate::ParaId::from(sender);
+					let recipient = $crate::ParaId::from(recipient);
 
-				sproof
-					.hrmp_channels
-					.entry(HrmpChannelId {
-						sender: sproof.para_id,
-						recipient: recipient_para_id,
-					})
-					.or_insert_with(|| AbridgedHrmpChannel {
-						max_capacity: 1024,
-						max_total_size: 1024 * 1024,
-						max_message_size: 1024 * 1024,
-						msg_count: 0,
-						total_size: 0,
-						mqc_head: Option::None,
-					});
+					if sender == sproof.para_id {
+						// egress channel
+						if let Err(idx) = e_index.binary_search(&recipient) {
+							e_index.insert(idx, recipient);
+						}
+					} else if recipient == sproof.para_id {
+						// ingress channel
+						if let Err(idx) = i_index.binary_search(&sender) {
+							i_index.insert(idx, sender);
+						}
+					} else {
+						continue;
+					}
+
+					let (msg_count, total_size) = $name::hrmp_channel_usage(sender.into(), recipient.into());
+					sproof
+						.hrmp_channels
+						.entry(HrmpChannelId { sender, recipient })
+						.or_insert_with(|| AbridgedHrmpChannel {
+							max_capacity: config.max_capacity,
+							max_total_size: config.max_total_size,
+							max_message_size: config.max_message_size,
+							msg_count,
+							total_size,
+							mqc_head: if recipient == sproof.para_id {
+								hrmp_mqc_heads.get(&sender).map(|head| head.head())
+							} else {
+								Option::None
+							},
+						});
+				}
 			}
 
 			let (relay_storage_root, proof) = sproof.into_state_root_and_proof();
@@ -458,9 +3989,191 @@ macro_rules! decl_test_network {
 					max_pov_size: Default::default(),
 				},
 				relay_chain_state: proof,
-				downward_messages: Default::default(),
-				horizontal_messages: Default::default(),
+				downward_messages,
+				horizontal_messages,
+			}
+		}
+	};
+}
+
+#[macro_export]
+macro_rules! decl_test_bridge {
+	(
+		pub struct $name:ident {
+			bridge_hubs = vec![ $( ($network_id:expr, $bridge_hub:ty, $router:ty), )* ],
+		}
+	) => {
+		/// Bridge relaying messages exported by bridge hubs to the bridge hub of the destination network,
+		/// which sends them on with its `XcmRouter`. Messages the bridge hub fails to send are reported by
+		/// `relayed_bridge_messages` of the destination network.
+		pub struct $name;
+
+		impl $crate::Bridge for $name {
+			fn relay_messages() -> bool {
+				use $crate::{Parachain, TestExt};
+
+				let mut relayed = false;
+				$(
+					while let Some(message) =
+						$crate::BRIDGED_MESSAGES.with(|b| b.borrow_mut().get_mut(&$network_id)?.pop_front())
+					{
+						relayed = true;
+						let bridge_hub = <$bridge_hub>::para_id();
+						let result = <$bridge_hub>::execute_with(|| {
+							$crate::dispatch_bridged_message::<$router>(message.clone(), bridge_hub)
+						});
+						$crate::RELAYED_BRIDGE_MESSAGES.with(|b| {
+							b.borrow_mut()
+								.entry($network_id)
+								.or_default()
+								.push($crate::RelayedBridgeMessage { message, result })
+						});
+					}
+				)*
+				relayed
+			}
+		}
+	};
+}
+
+/// Assert events matching `patterns` are in the current block of the chain `$chain`, each pattern
+/// matching its own event for which its conditions hold, so a pattern repeated `n` times needs `n`
+/// events. Conditions are labelled, and can use the bindings of the pattern. With `in order`, the
+/// events must be in the order of the patterns. With `only`, no other event may match one of the
+/// patterns, so repeating a pattern `n` times asserts exactly `n` such events. On failure, the
+/// patterns not matched and every event of the block are printed.
+///
+/// ```ignore
+/// assert_expected_events!(YayoiMushroom, in order [
+/// 	RuntimeEvent::System(frame_system::Event::Remarked { hash, .. }) => { hash: *hash == expected },
+/// 	RuntimeEvent::XcmpQueue(cumulus_pallet_xcmp_queue::Event::Success { .. }),
+/// ]);
+/// ```
+#[macro_export]
+macro_rules! assert_expected_events {
+	(
+		@impl $chain:ty, $in_order:expr, $only:expr,
+		[ $( $pattern:pat => { $( $field:ident : $condition:expr, )* } ),* ]
+	) => {{
+		let events = <$chain as $crate::Chain>::events();
+		let mut matched = Vec::<usize>::new();
+		let mut failures = Vec::<String>::new();
+		$(
+			let from = if $in_order { matched.last().map_or(0, |index| index + 1) } else { 0 };
+			let mut unmet = None;
+			// an event matches at most one pattern, also when the order is not checked
+			let mut candidates =
+				events.iter().enumerate().skip(from).filter(|(index, _)| !matched.contains(index));
+			let found = candidates.find_map(|(index, event)| match event {
+				#[allow(unused_variables)]
+				$pattern => {
+					#[allow(unused_mut)]
+					let mut unmet_conditions = Vec::<&'static str>::new();
+					$(
+						if !$condition {
+							unmet_conditions.push(stringify!($field));
+						}
+					)*
+					if unmet_conditions.is_empty() {
+						Some(index)
+					} else {
+						unmet = Some(unmet_conditions);
+						None
+					}
+				}
+				_ => None,
+			});
+			match (found, unmet) {
+				(Some(index), _) => matched.push(index),
+				(None, Some(conditions)) => failures.push(format!(
+					"{} matched, but not its conditions {:?}",
+					stringify!($pattern),
+					conditions,
+				)),
+				(None, None) => failures.push(format!("{} not found", stringify!($pattern))),
 			}
+		)*
+		if $only {
+			$(
+				let unexpected = events.iter().enumerate().find(|(index, event)| {
+					!matched.contains(index) && match event {
+						#[allow(unused_variables)]
+						$pattern => true $( && $condition )*,
+						_ => false,
+					}
+				});
+				if let Some((_, event)) = unexpected {
+					failures.push(format!("{} also matched by {:?}", stringify!($pattern), event));
+				}
+			)*
+		}
+		if !failures.is_empty() {
+			panic!(
+				"expected events of {} not found:\n  - {}\nevents:\n{:#?}",
+				stringify!($chain),
+				failures.join("\n  - "),
+				events,
+			);
 		}
+	}};
+	(
+		$chain:ty, in order [
+			$( $pattern:pat $( => { $( $field:ident : $condition:expr ),* $(,)? } )? ),* $(,)?
+		] $(,)?
+	) => {
+		$crate::assert_expected_events!(
+			@impl $chain, true, false, [ $( $pattern => { $( $( $field : $condition, )* )? } ),* ]
+		)
+	};
+	(
+		$chain:ty, only [
+			$( $pattern:pat $( => { $( $field:ident : $condition:expr ),* $(,)? } )? ),* $(,)?
+		] $(,)?
+	) => {
+		$crate::assert_expected_events!(
+			@impl $chain, false, true, [ $( $pattern => { $( $( $field : $condition, )* )? } ),* ]
+		)
+	};
+	(
+		$chain:ty, [
+			$( $pattern:pat $( => { $( $field:ident : $condition:expr ),* $(,)? } )? ),* $(,)?
+		] $(,)?
+	) => {
+		$crate::assert_expected_events!(
+			@impl $chain, false, false, [ $( $pattern => { $( $( $field : $condition, )* )? } ),* ]
+		)
 	};
 }
+
+/// Assert no event in the current block of the chain `$chain` matches any of `patterns` with its
+/// conditions holding, as in `assert_expected_events!`. On failure, the matching events and every
+/// event of the block are printed.
+#[macro_export]
+macro_rules! assert_no_events {
+	(
+		$chain:ty, [
+			$( $pattern:pat $( => { $( $field:ident : $condition:expr ),* $(,)? } )? ),* $(,)?
+		] $(,)?
+	) => {{
+		let events = <$chain as $crate::Chain>::events();
+		let mut failures = Vec::<String>::new();
+		$(
+			let found = events.iter().find(|event| match event {
+				#[allow(unused_variables)]
+				$pattern => true $( $( && $condition )* )?,
+				_ => false,
+			});
+			if let Some(event) = found {
+				failures.push(format!("{} matched by {:?}", stringify!($pattern), event));
+			}
+		)*
+		if !failures.is_empty() {
+			panic!(
+				"unexpected events of {}:\n  - {}\nevents:\n{:#?}",
+				stringify!($chain),
+				failures.join("\n  - "),
+				events,
+			);
+		}
+	}};
+}
@@ -1,7 +1,8 @@
+use cumulus_pallet_parachain_system::{CheckAssociatedRelayNumber, RelayNumberStrictlyIncreases};
 use frame_support::traits::ConstU32;
 use frame_support::{
 	construct_runtime, parameter_types,
-	traits::{Everything, Nothing},
+	traits::{Equals, Everything, Nothing},
 	weights::{constants::WEIGHT_REF_TIME_PER_SECOND, Weight},
 };
 use frame_system::EnsureRoot;
@@ -82,11 +83,22 @@ impl parachain_info::Config for Runtime {}
 
 parameter_types! {
 	pub const RelayLocation: MultiLocation = MultiLocation::parent();
-	pub const RelayNetwork: NetworkId = NetworkId::Kusama;
+	pub storage RelayNetwork: NetworkId = NetworkId::Kusama;
 	pub RelayChainOrigin: RuntimeOrigin = cumulus_pallet_xcm::Origin::Relay.into();
 	pub UniversalLocation: InteriorMultiLocation = X2(GlobalConsensus(RelayNetwork::get()), Parachain(ParachainInfo::parachain_id().into()));
 }
 
+parameter_types! {
+	/// Network bridged with the one of the relay chain.
+	pub BridgedNetwork: NetworkId = match RelayNetwork::get() {
+		NetworkId::Kusama => NetworkId::Polkadot,
+		_ => NetworkId::Kusama,
+	};
+	/// The bridge hub, parachain 2, is the only location messages of the bridged network come from.
+	pub BridgedNetworkAlias: (MultiLocation, Junction) =
+		(MultiLocation::new(1, X1(Parachain(2))), GlobalConsensus(BridgedNetwork::get()));
+}
+
 pub type LocationToAccountId = (
 	ParentIsPreset<AccountId>,
 	SiblingParachainConvertsVia<Sibling, AccountId>,
@@ -120,6 +132,13 @@ pub type XcmRouter = (
 
 pub type Barrier = AllowUnpaidExecutionFrom<Everything>;
 
+/// Exports messages to bridged networks. Exported messages are kept by the emulator, with the
+/// `emulated-bridge` feature for tests.
+#[cfg(feature = "emulated-bridge")]
+pub type MessageExporter = xcm_emulator::BridgeMessageExporter;
+#[cfg(not(feature = "emulated-bridge"))]
+pub type MessageExporter = ();
+
 pub struct XcmConfig;
 impl Config for XcmConfig {
 	type RuntimeCall = RuntimeCall;
@@ -141,15 +160,33 @@ impl Config for XcmConfig {
 	type PalletInstancesInfo = ();
 	type MaxAssetsIntoHolding = MaxAssetsIntoHolding;
 	type FeeManager = ();
-	type MessageExporter = ();
-	type UniversalAliases = Nothing;
+	type MessageExporter = MessageExporter;
+	type UniversalAliases = Equals<BridgedNetworkAlias>;
 	type CallDispatcher = RuntimeCall;
 	type SafeCallFilter = Everything;
 }
 
 parameter_types! {
-	pub const ReservedXcmpWeight: Weight = Weight::from_parts(WEIGHT_REF_TIME_PER_SECOND.saturating_div(4), 0);
-	pub const ReservedDmpWeight: Weight = Weight::from_parts(WEIGHT_REF_TIME_PER_SECOND.saturating_div(4), 0);
+	pub const ReservedXcmpWeight: Weight = Weight::from_parts(WEIGHT_REF_TIME_PER_SECOND.saturating_div(4), 1024 * 1024);
+	pub const ReservedDmpWeight: Weight = Weight::from_parts(WEIGHT_REF_TIME_PER_SECOND.saturating_div(4), 1024 * 1024);
+	pub storage AsyncBacking: bool = false;
+}
+
+/// Relay parents must strictly increase between blocks, or only not decrease with `AsyncBacking`.
+///
+/// `cumulus_pallet_parachain_system` of this version only checks strictly increasing relay parents,
+/// with `RelayNumberStrictlyIncreases`, hence this checker for async backing.
+pub struct CheckRelayNumber;
+
+impl CheckAssociatedRelayNumber for CheckRelayNumber {
+	fn check_associated_relay_number(current: u32, previous: u32) {
+		if !AsyncBacking::get() {
+			return RelayNumberStrictlyIncreases::check_associated_relay_number(current, previous);
+		}
+		if current < previous {
+			panic!("Relay chain block number needs to increase between Parachain blocks!")
+		}
+	}
 }
 
 impl cumulus_pallet_parachain_system::Config for Runtime {
@@ -161,7 +198,7 @@ impl cumulus_pallet_parachain_system::Config for Runtime {
 	type OutboundXcmpMessageSource = XcmpQueue;
 	type XcmpMessageHandler = XcmpQueue;
 	type ReservedXcmpWeight = ReservedXcmpWeight;
-	type CheckAssociatedRelayNumber = cumulus_pallet_parachain_system::RelayNumberStrictlyIncreases;
+	type CheckAssociatedRelayNumber = CheckRelayNumber;
 }
 
 impl cumulus_pallet_xcmp_queue::Config for Runtime {